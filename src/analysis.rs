@@ -0,0 +1,601 @@
+use crate::decoder;
+use crate::error::StegError;
+use image::{DynamicImage, GrayImage, ImageFormat};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Number of growing prefixes of a channel the chi-square attack is run on
+const CHI_SQUARE_STEPS: usize = 100;
+// Pairs of values expected fewer times than this in a window are left out of the chi-square test
+const CHI_SQUARE_MIN_EXPECTED: f64 = 5.0;
+// Consecutive samples of a channel grouped together by RS analysis, and the mask flipping them
+const RS_MASK: [i8; 4] = [0, 1, 1, 0];
+
+// Payload estimated in a channel: the share of its samples whose LSB carries data, from 0 to 1,
+// and the length of that data in bytes at one bit per sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LengthEstimate {
+    pub rate: f64,
+    pub bytes: u64,
+}
+
+impl LengthEstimate {
+    fn new(rate: f64, samples: usize) -> Self {
+        Self {
+            rate,
+            bytes: (rate * samples as f64 / 8.0).round() as u64,
+        }
+    }
+}
+
+// The samples of an image split by channel, as the decoder reads them: the low byte of every
+// sample, which is where data would be hidden
+pub struct ImageChannels {
+    width: u32,
+    height: u32,
+    channels: Vec<Vec<u8>>,
+}
+
+impl ImageChannels {
+    // Reads an image in any of the formats the decoder supports
+    pub fn read<R: Read>(input: &mut R) -> Result<Self, StegError> {
+        Ok(Self::from_image(&decoder::load_image(input)?))
+    }
+
+    pub fn from_image(img: &DynamicImage) -> Self {
+        let bytes = decoder::image_bytes(img);
+        let pixels = (img.width() as usize * img.height() as usize).max(1);
+        let channel_count = (bytes.len() / pixels).max(1);
+
+        let channels = (0..channel_count)
+            .map(|c| {
+                bytes
+                    .iter()
+                    .skip(c)
+                    .step_by(channel_count)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            width: img.width(),
+            height: img.height(),
+            channels,
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    // Bit 0 (the LSB) to 7 of every sample of the channel as a grayscale image, where set bits
+    // are white and cleared ones black. Sequentially embedded data shows up as noise at the top
+    // of the LSB plane
+    pub fn bit_plane(&self, channel: usize, bit: u8) -> Option<GrayImage> {
+        let samples = self.channels.get(channel).filter(|_| bit < 8)?;
+        let plane = samples
+            .iter()
+            .map(|sample| if (sample >> bit) & 1 == 1 { 0xFF } else { 0x00 })
+            .collect();
+
+        GrayImage::from_raw(self.width, self.height, plane)
+    }
+
+    // Writes the bit_plane of every channel and bit into the directory as a PNG image named
+    // channel-<channel>-bit-<bit>.png, returning the paths of the images
+    pub fn write_bit_planes<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>, StegError> {
+        let mut paths = Vec::new();
+
+        for channel in 0..self.channel_count() {
+            for bit in 0..8 {
+                let path = dir
+                    .as_ref()
+                    .join(format!("channel-{}-bit-{}.png", channel, bit));
+                if let Some(plane) = self.bit_plane(channel, bit) {
+                    plane.save_with_format(&path, ImageFormat::Png)?;
+                    paths.push(path);
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    // Westfeld and Pfitzmann's chi-square attack. LSB replacement of random data evens out the
+    // counts of the values 2k and 2k + 1, the attack tests growing prefixes of the samples for
+    // this. Returns, for every channel, the longest prefix that looks embedded into as a share of
+    // the samples. It detects sequential embedding, data scattered across the image only shows
+    // when most of the image carries data
+    pub fn chi_square(&self) -> Vec<f64> {
+        self.channels
+            .iter()
+            .map(|samples| {
+                let embedded = (1..=CHI_SQUARE_STEPS)
+                    .rev()
+                    .find(|step| {
+                        let prefix = &samples[..samples.len() * step / CHI_SQUARE_STEPS];
+                        chi_square_probability(prefix) > 0.5
+                    })
+                    .unwrap_or(0);
+                embedded as f64 / CHI_SQUARE_STEPS as f64
+            })
+            .collect()
+    }
+
+    // Fridrich, Goljan and Du's RS analysis. Groups of samples are classified as regular or
+    // singular depending on whether flipping their LSBs makes them noisier, the proportions of
+    // both change predictably with the share of samples whose LSB was replaced. Returns that
+    // share for every channel, from 0 to 1
+    pub fn rs_analysis(&self) -> Vec<f64> {
+        self.channels
+            .iter()
+            .map(|samples| rs_estimate(samples))
+            .collect()
+    }
+
+    // Dumitrescu, Wu and Wang's sample pairs analysis. Pairs of neighbouring samples fall into
+    // sets whose sizes LSB replacement changes in a known way, which gives the share of samples
+    // carrying data and the length of the payload for every channel
+    pub fn sample_pairs(&self) -> Vec<LengthEstimate> {
+        self.channels
+            .iter()
+            .map(|samples| LengthEstimate::new(sample_pairs_estimate(samples), samples.len()))
+            .collect()
+    }
+
+    // Looks at the histogram of every channel: LSB replacement evens out the counts of the values
+    // 2k and 2k + 1, but not of 2k + 1 and 2k + 2. In a cover image both differences are about
+    // the same size, so how much smaller the first ones are estimates the share of samples
+    // carrying data and the length of the payload. This needs a histogram that is not smooth, as
+    // in images whose contrast was stretched, smooth ones give 0
+    pub fn histogram_analysis(&self) -> Vec<LengthEstimate> {
+        self.channels
+            .iter()
+            .map(|samples| LengthEstimate::new(histogram_estimate(samples), samples.len()))
+            .collect()
+    }
+}
+
+// Probability that the window holds embedded data, from the chi-square statistic of the pairs of
+// values against their expected equal counts
+fn chi_square_probability(window: &[u8]) -> f64 {
+    let histogram = histogram(window);
+
+    let mut statistic = 0.0;
+    let mut categories = 0;
+    for pair in histogram.chunks(2) {
+        let expected = (pair[0] + pair[1]) as f64 / 2.0;
+        if expected < CHI_SQUARE_MIN_EXPECTED {
+            continue;
+        }
+
+        statistic += (pair[0] as f64 - expected).powi(2) / expected;
+        categories += 1;
+    }
+
+    if categories < 2 {
+        return 0.0;
+    }
+
+    1.0 - regularized_gamma((categories - 1) as f64 / 2.0, statistic / 2.0)
+}
+
+fn histogram(samples: &[u8]) -> [usize; 256] {
+    let mut histogram = [0; 256];
+    samples.iter().for_each(|s| histogram[*s as usize] += 1);
+    histogram
+}
+
+fn rs_estimate(samples: &[u8]) -> f64 {
+    let flipped: Vec<u8> = samples.iter().map(|s| s ^ 1).collect();
+
+    let negative_mask = RS_MASK.map(|m| -m);
+    let (r, s) = regular_singular(samples, &RS_MASK);
+    let (r_neg, s_neg) = regular_singular(samples, &negative_mask);
+    let (r_flipped, s_flipped) = regular_singular(&flipped, &RS_MASK);
+    let (r_neg_flipped, s_neg_flipped) = regular_singular(&flipped, &negative_mask);
+
+    let d0 = r - s;
+    let d1 = r_flipped - s_flipped;
+    let d_neg0 = r_neg - s_neg;
+    let d_neg1 = r_neg_flipped - s_neg_flipped;
+
+    // The share z of flipped LSBs solves 2(d1 + d0)z^2 + (d-0 - d-1 - d1 - 3d0)z + d0 - d-0 = 0,
+    // the smaller root is the one that applies
+    let a = 2.0 * (d1 + d0);
+    let b = d_neg0 - d_neg1 - d1 - 3.0 * d0;
+    let c = d0 - d_neg0;
+
+    let z = if a.abs() < f64::EPSILON {
+        if b.abs() < f64::EPSILON {
+            return 0.0;
+        }
+        -c / b
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return 0.0;
+        }
+        let roots = [
+            (-b + discriminant.sqrt()) / (2.0 * a),
+            (-b - discriminant.sqrt()) / (2.0 * a),
+        ];
+        if roots[0].abs() < roots[1].abs() {
+            roots[0]
+        } else {
+            roots[1]
+        }
+    };
+
+    if (z - 0.5).abs() < f64::EPSILON {
+        return 0.0;
+    }
+    (z / (z - 0.5)).clamp(0.0, 1.0)
+}
+
+fn sample_pairs_estimate(samples: &[u8]) -> f64 {
+    // x: the even sample of the pair is the larger, y: the odd one is, z: both samples only
+    // differ in their LSB
+    let mut x = 0;
+    let mut y = 0;
+    let mut z = 0;
+    let mut pairs = 0;
+
+    for pair in samples.windows(2) {
+        let (u, v) = (pair[0], pair[1]);
+        if (v % 2 == 0 && u < v) || (v % 2 == 1 && u > v) {
+            x += 1;
+        }
+        if (v % 2 == 0 && u > v) || (v % 2 == 1 && u < v) {
+            y += 1;
+        }
+        if u / 2 == v / 2 {
+            z += 1;
+        }
+        pairs += 1;
+    }
+
+    if z == 0 {
+        return 0.0;
+    }
+
+    // The share b of flipped LSBs solves 2z b^2 + 2(2x - pairs)b + y - x = 0, the smaller root
+    // is the one that applies. Half the samples carrying data have their LSB flipped
+    let a = 2.0 * z as f64;
+    let b = 2.0 * (2.0 * x as f64 - pairs as f64);
+    let c = y as f64 - x as f64;
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return 0.0;
+    }
+
+    let flipped =
+        ((-b + discriminant.sqrt()) / (2.0 * a)).min((-b - discriminant.sqrt()) / (2.0 * a));
+    (2.0 * flipped).clamp(0.0, 1.0)
+}
+
+fn histogram_estimate(samples: &[u8]) -> f64 {
+    let histogram = histogram(samples);
+
+    // Squared differences between the counts of neighbouring values, relative to their sampling
+    // noise, so this is 1 when the counts only differ by chance
+    let dispersion = |pairs: &mut dyn Iterator<Item = (usize, usize)>| {
+        let (squares, counts) = pairs.fold((0.0, 0.0), |(squares, counts), (a, b)| {
+            (
+                squares + (a as f64 - b as f64).powi(2),
+                counts + (a + b) as f64,
+            )
+        });
+        if counts > 0.0 {
+            squares / counts
+        } else {
+            1.0
+        }
+    };
+    let within = dispersion(&mut (0..128).map(|k| (histogram[2 * k], histogram[2 * k + 1])));
+    let across = dispersion(&mut (0..127).map(|k| (histogram[2 * k + 1], histogram[2 * k + 2])));
+
+    // Replacing the LSB of a share p of the samples scales the differences within pairs by
+    // 1 - p, the differences across pairs stay the same
+    if across <= 1.0 {
+        return 0.0;
+    }
+    let remaining = ((within - 1.0) / (across - 1.0)).clamp(0.0, 1.0);
+    1.0 - remaining.sqrt()
+}
+
+// Shares of the groups of samples that get noisier (regular) and smoother (singular) when the
+// mask is applied to them
+fn regular_singular(samples: &[u8], mask: &[i8; 4]) -> (f64, f64) {
+    let mut regular = 0;
+    let mut singular = 0;
+    let mut groups = 0;
+
+    for group in samples.chunks_exact(mask.len()) {
+        let flipped: Vec<i16> = group
+            .iter()
+            .zip(mask.iter())
+            .map(|(sample, m)| flip(*sample as i16, *m))
+            .collect();
+        let original: Vec<i16> = group.iter().map(|sample| *sample as i16).collect();
+
+        let before = smoothness(&original);
+        let after = smoothness(&flipped);
+        if after > before {
+            regular += 1;
+        } else if after < before {
+            singular += 1;
+        }
+        groups += 1;
+    }
+
+    if groups == 0 {
+        return (0.0, 0.0);
+    }
+    (
+        regular as f64 / groups as f64,
+        singular as f64 / groups as f64,
+    )
+}
+
+// Flipping 1 swaps 2k and 2k + 1, flipping -1 swaps 2k - 1 and 2k
+fn flip(sample: i16, direction: i8) -> i16 {
+    match direction {
+        1 => sample ^ 1,
+        -1 => ((sample + 1) ^ 1) - 1,
+        _ => sample,
+    }
+}
+
+// Sum of the differences between neighbouring samples, higher for noisier groups
+fn smoothness(group: &[i16]) -> i32 {
+    group
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).abs() as i32)
+        .sum()
+}
+
+// Regularised lower incomplete gamma function P(a, x), which gives the chi-square distribution
+// function with 2a degrees of freedom at 2x
+fn regularized_gamma(a: f64, x: f64) -> f64 {
+    const EPSILON: f64 = 1e-12;
+    const MAX_ITERATIONS: usize = 1000;
+
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    let prefactor = (-x + a * x.ln() - ln_gamma(a)).exp();
+
+    if x < a + 1.0 {
+        // Series expansion
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        let mut n = a;
+        for _ in 0..MAX_ITERATIONS {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * EPSILON {
+                break;
+            }
+        }
+        (sum * prefactor).min(1.0)
+    } else {
+        // Continued fraction for the upper function Q(a, x), evaluated with Lentz's method
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut fraction = d;
+        for i in 1..MAX_ITERATIONS {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            fraction *= delta;
+            if (delta - 1.0).abs() < EPSILON {
+                break;
+            }
+        }
+        (1.0 - prefactor * fraction).max(0.0)
+    }
+}
+
+// Natural logarithm of the gamma function, Lanczos approximation
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000000000190015;
+    let mut y = x;
+    for c in COEFFICIENTS.iter() {
+        y += 1.0;
+        series += c / y;
+    }
+
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    // Smooth grayscale image with some noise
+    fn cover(rng: &mut ChaCha20Rng) -> Vec<u8> {
+        (0..128 * 128)
+            .map(|i| {
+                let (x, y) = ((i % 128) as f64 / 20.0, (i / 128) as f64 / 15.0);
+                let value = 128.0 + 60.0 * x.sin() * y.cos() + rng.gen_range(-3.0..3.0);
+                value.round() as u8
+            })
+            .collect()
+    }
+
+    fn channels(samples: Vec<u8>) -> ImageChannels {
+        let img = image::GrayImage::from_raw(128, 128, samples).unwrap();
+        ImageChannels::from_image(&DynamicImage::ImageLuma8(img))
+    }
+
+    #[test]
+    fn test_chi_square() {
+        let mut rng = ChaCha20Rng::from_seed([1; 32]);
+        // Only even values, as in images scaled up from 7 bits, so the pairs of values are
+        // as uneven as they get
+        let mut samples: Vec<u8> = cover(&mut rng).iter().map(|s| s & 0xFE).collect();
+        assert!(channels(samples.clone()).chi_square()[0] < 0.05);
+
+        // Sequential embedding into the first half of the image
+        let half = samples.len() / 2;
+        samples[..half]
+            .iter_mut()
+            .for_each(|s| *s = (*s & 0xFE) | rng.gen::<bool>() as u8);
+
+        let estimate = channels(samples).chi_square()[0];
+        assert!((0.45..0.6).contains(&estimate), "estimate: {}", estimate);
+    }
+
+    #[test]
+    fn test_rs_analysis() {
+        let mut rng = ChaCha20Rng::from_seed([2; 32]);
+        let mut samples = cover(&mut rng);
+        assert!(channels(samples.clone()).rs_analysis()[0] < 0.1);
+
+        // Random bits in half of the samples, scattered across the image
+        for s in samples.iter_mut() {
+            if rng.gen::<bool>() {
+                *s = (*s & 0xFE) | rng.gen::<bool>() as u8;
+            }
+        }
+
+        let estimate = channels(samples).rs_analysis()[0];
+        assert!((0.4..0.65).contains(&estimate), "estimate: {}", estimate);
+    }
+
+    #[test]
+    fn test_sample_pairs() {
+        let mut rng = ChaCha20Rng::from_seed([3; 32]);
+        let mut samples = cover(&mut rng);
+        assert!(channels(samples.clone()).sample_pairs()[0].rate < 0.1);
+
+        for s in samples.iter_mut() {
+            if rng.gen::<bool>() {
+                *s = (*s & 0xFE) | rng.gen::<bool>() as u8;
+            }
+        }
+
+        let estimate = channels(samples).sample_pairs()[0];
+        assert!(
+            (0.4..0.65).contains(&estimate.rate),
+            "estimate: {:?}",
+            estimate
+        );
+        // 128 * 128 samples at one bit each
+        assert!(
+            (820..1330).contains(&estimate.bytes),
+            "estimate: {:?}",
+            estimate
+        );
+    }
+
+    #[test]
+    fn test_histogram_analysis() {
+        let mut rng = ChaCha20Rng::from_seed([4; 32]);
+        // Stretching the contrast leaves some values unused
+        let mut samples: Vec<u8> = cover(&mut rng)
+            .iter()
+            .map(|s| (*s as f64 * 1.4 - 50.0).round() as u8)
+            .collect();
+        assert!(channels(samples.clone()).histogram_analysis()[0].rate < 0.1);
+
+        for s in samples.iter_mut() {
+            if rng.gen::<bool>() {
+                *s = (*s & 0xFE) | rng.gen::<bool>() as u8;
+            }
+        }
+
+        let estimate = channels(samples).histogram_analysis()[0];
+        assert!(
+            (0.35..0.65).contains(&estimate.rate),
+            "estimate: {:?}",
+            estimate
+        );
+        assert!(
+            (710..1330).contains(&estimate.bytes),
+            "estimate: {:?}",
+            estimate
+        );
+    }
+
+    #[test]
+    fn test_length_estimate() {
+        assert_eq!(
+            LengthEstimate {
+                rate: 0.5,
+                bytes: 50
+            },
+            LengthEstimate::new(0.5, 800)
+        );
+        assert_eq!(0, LengthEstimate::new(0.0, 800).bytes);
+    }
+
+    #[test]
+    fn test_channels() {
+        let img = image::RgbaImage::from_fn(2, 1, |x, _| image::Rgba([x as u8, 10, 20, 255]));
+        let channels = ImageChannels::from_image(&DynamicImage::ImageRgba8(img));
+
+        assert_eq!(4, channels.channel_count());
+        assert_eq!(vec![0, 1], channels.channels[0]);
+        assert_eq!(vec![255, 255], channels.channels[3]);
+    }
+
+    #[test]
+    fn test_bit_plane() {
+        let img = image::RgbImage::from_fn(2, 2, |x, y| image::Rgb([(x + 2 * y) as u8, 0x80, 0]));
+        let channels = ImageChannels::from_image(&DynamicImage::ImageRgb8(img));
+
+        let lsb = channels.bit_plane(0, 0).unwrap();
+        assert_eq!((2, 2), lsb.dimensions());
+        assert_eq!(vec![0x00, 0xFF, 0x00, 0xFF], lsb.into_raw());
+        assert_eq!(
+            vec![0x00, 0x00, 0xFF, 0xFF],
+            channels.bit_plane(0, 1).unwrap().into_raw()
+        );
+        assert_eq!(vec![0xFF; 4], channels.bit_plane(1, 7).unwrap().into_raw());
+
+        assert!(channels.bit_plane(3, 0).is_none());
+        assert!(channels.bit_plane(0, 8).is_none());
+    }
+
+    #[test]
+    fn test_regularized_gamma() {
+        // P(1, x) = 1 - e^-x
+        for x in [0.1, 1.0, 5.0].iter() {
+            assert!((regularized_gamma(1.0, *x) - (1.0 - (-x).exp())).abs() < 1e-9);
+        }
+        // Median of the chi-square distribution with 2 degrees of freedom
+        assert!((regularized_gamma(1.0, 2f64.ln()) - 0.5).abs() < 1e-9);
+        // P(3, 2) = 1 - 5e^-2
+        assert!((regularized_gamma(3.0, 2.0) - (1.0 - 5.0 * (-2f64).exp())).abs() < 1e-9);
+    }
+}
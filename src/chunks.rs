@@ -0,0 +1,185 @@
+use std::convert::TryInto;
+
+const SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+// length + type + crc
+const CHUNK_OVERHEAD: usize = 12;
+
+// Ancillary chunks that describe the pixel data itself, they are only valid in the output if the
+// colour type and bit depth did not change
+const PIXEL_FORMAT_CHUNKS: [&[u8; 4]; 4] = [b"tRNS", b"bKGD", b"sBIT", b"hIST"];
+// Animation chunks, the output only holds the first frame
+const ANIMATION_CHUNKS: [&[u8; 4]; 3] = [b"acTL", b"fcTL", b"fdAT"];
+
+struct Chunk<'a> {
+    chunk_type: [u8; 4],
+    // The whole chunk, including its length and crc
+    raw: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    fn is_ancillary(&self) -> bool {
+        self.chunk_type[0].is_ascii_lowercase()
+    }
+
+    fn is(&self, chunk_type: &[u8; 4]) -> bool {
+        self.chunk_type == *chunk_type
+    }
+}
+
+fn read_chunks(png: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    if !png.starts_with(&SIGNATURE) {
+        return Err("not a PNG image".to_string());
+    }
+
+    let mut chunks = Vec::new();
+    let mut rest = &png[SIGNATURE.len()..];
+
+    while !rest.is_empty() {
+        if rest.len() < CHUNK_OVERHEAD {
+            return Err("truncated PNG chunk".to_string());
+        }
+
+        let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let end = length
+            .checked_add(CHUNK_OVERHEAD)
+            .filter(|end| *end <= rest.len())
+            .ok_or_else(|| "truncated PNG chunk".to_string())?;
+
+        chunks.push(Chunk {
+            chunk_type: rest[4..8].try_into().unwrap(),
+            raw: &rest[..end],
+        });
+        rest = &rest[end..];
+    }
+
+    Ok(chunks)
+}
+
+// Copies the ancillary chunks of the cover into the output, in their original order: the ones
+// that came before the image data go right after IHDR, the others right before IEND
+pub fn copy_ancillary(cover: &[u8], output: &[u8]) -> Result<Vec<u8>, String> {
+    let cover_chunks = read_chunks(cover)?;
+    let output_chunks = read_chunks(output)?;
+
+    let ihdr = |chunks: &[Chunk<'_>]| {
+        chunks
+            .first()
+            .filter(|chunk| chunk.is(b"IHDR"))
+            .map(|chunk| chunk.raw.to_vec())
+            .ok_or_else(|| "PNG image does not start with IHDR".to_string())
+    };
+    // Bit depth and colour type are the 9th and 10th byte of the IHDR data
+    let same_pixel_format = ihdr(&cover_chunks)?[16..18] == ihdr(&output_chunks)?[16..18];
+
+    let first_idat = cover_chunks
+        .iter()
+        .position(|chunk| chunk.is(b"IDAT"))
+        .ok_or_else(|| "PNG image has no IDAT chunk".to_string())?;
+
+    let copied = |chunk: &&Chunk<'_>| {
+        chunk.is_ancillary()
+            && !ANIMATION_CHUNKS.iter().any(|t| chunk.is(t))
+            && (same_pixel_format || !PIXEL_FORMAT_CHUNKS.iter().any(|t| chunk.is(t)))
+    };
+    let before = cover_chunks[..first_idat].iter().filter(copied);
+    let after = cover_chunks[first_idat..].iter().filter(copied);
+
+    let mut out = Vec::with_capacity(cover.len() + output.len());
+    out.extend_from_slice(&SIGNATURE);
+
+    for chunk in output_chunks.iter() {
+        if chunk.is(b"IEND") {
+            after.clone().for_each(|c| out.extend_from_slice(c.raw));
+        }
+
+        out.extend_from_slice(chunk.raw);
+
+        if chunk.is(b"IHDR") {
+            before.clone().for_each(|c| out.extend_from_slice(c.raw));
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Chunk with a dummy crc, the chunks are copied verbatim so it is never checked
+    fn chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(chunk_type);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&[0; 4]);
+        out
+    }
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+        chunks.iter().for_each(|c| out.extend_from_slice(c));
+        out
+    }
+
+    fn ihdr(colour_type: u8) -> Vec<u8> {
+        chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, colour_type, 0, 0, 0])
+    }
+
+    fn chunk_types(png: &[u8]) -> Vec<[u8; 4]> {
+        read_chunks(png)
+            .unwrap()
+            .iter()
+            .map(|chunk| chunk.chunk_type)
+            .collect()
+    }
+
+    #[test]
+    fn test_copy_ancillary() {
+        let cover = png(&[
+            ihdr(2),
+            chunk(b"iCCP", b"profile"),
+            chunk(b"pHYs", &[0; 9]),
+            chunk(b"tRNS", &[0; 6]),
+            chunk(b"IDAT", b"cover"),
+            chunk(b"tIME", &[0; 7]),
+            chunk(b"tEXt", b"Comment\0hi"),
+            chunk(b"IEND", &[]),
+        ]);
+        let output = png(&[ihdr(6), chunk(b"IDAT", b"output"), chunk(b"IEND", &[])]);
+
+        let copied = copy_ancillary(&cover, &output).expect("no error");
+
+        assert_eq!(
+            vec![*b"IHDR", *b"iCCP", *b"pHYs", *b"IDAT", *b"tIME", *b"tEXt", *b"IEND"],
+            chunk_types(&copied)
+        );
+        assert!(copied
+            .windows(b"IDAToutput".len())
+            .any(|w| w == b"IDAToutput"));
+    }
+
+    #[test]
+    fn test_copy_pixel_format_chunks_when_unchanged() {
+        let cover = png(&[
+            ihdr(2),
+            chunk(b"tRNS", &[0; 6]),
+            chunk(b"IDAT", b"cover"),
+            chunk(b"IEND", &[]),
+        ]);
+        let output = png(&[ihdr(2), chunk(b"IDAT", b"output"), chunk(b"IEND", &[])]);
+
+        let copied = copy_ancillary(&cover, &output).expect("no error");
+
+        assert_eq!(
+            vec![*b"IHDR", *b"tRNS", *b"IDAT", *b"IEND"],
+            chunk_types(&copied)
+        );
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        let mut cover = png(&[ihdr(2), chunk(b"IDAT", b"cover")]);
+        cover.pop();
+        assert!(read_chunks(&cover).is_err());
+    }
+}
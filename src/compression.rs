@@ -0,0 +1,211 @@
+use crate::error::StegError;
+use crate::CompressInput;
+use flate2::read::GzEncoder;
+use std::io::Read;
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+// Codecs Auto picks from, at the levels it uses. Xz presets above 6 only grow the dictionary past
+// 8 MiB, which costs hundreds of MiB of memory and does not help smaller payloads
+const AUTO_CODECS: [CompressInput; 4] = [
+    CompressInput::Gzip,
+    CompressInput::Zstd(19),
+    CompressInput::Brotli(11),
+    CompressInput::Xz(6),
+];
+
+// Limits the level to the range the codec supports
+pub fn clamp(compress_input: CompressInput) -> CompressInput {
+    match compress_input {
+        CompressInput::Zstd(level) => {
+            let range = zstd::compression_level_range();
+            CompressInput::Zstd(level.clamp(*range.start(), *range.end()))
+        }
+        CompressInput::Brotli(level) => CompressInput::Brotli(level.min(11)),
+        CompressInput::Xz(level) => CompressInput::Xz(level.min(9)),
+        compress_input => compress_input,
+    }
+}
+
+// Reader of the input compressed with the codec, which must not be Auto
+pub fn compress<'a, R: Read + 'a>(
+    compress_input: CompressInput,
+    input: R,
+) -> Result<Box<dyn Read + 'a>, StegError> {
+    Ok(match compress_input {
+        CompressInput::None => Box::new(input),
+        CompressInput::Gzip => Box::new(GzEncoder::new(input, flate2::Compression::default())),
+        CompressInput::Zstd(level) => Box::new(
+            zstd::stream::read::Encoder::new(input, level).map_err(StegError::Compression)?,
+        ),
+        CompressInput::Brotli(level) => Box::new(brotli::CompressorReader::new(
+            input,
+            BROTLI_BUFFER_SIZE,
+            level,
+            BROTLI_WINDOW_BITS,
+        )),
+        CompressInput::Xz(level) => Box::new(xz2::read::XzEncoder::new(input, level)),
+        CompressInput::Auto => unreachable!("Auto is resolved to a codec before compressing"),
+    })
+}
+
+// Compresses the input with every codec Auto picks from, returning the codec with the smallest
+// output, or None if no codec makes the input smaller
+pub fn compress_smallest(input: &[u8]) -> Result<(CompressInput, Vec<u8>), StegError> {
+    let mut smallest = (CompressInput::None, input.to_vec());
+    for compress_input in AUTO_CODECS.iter() {
+        let mut compressed = Vec::new();
+        compress(*compress_input, input)?
+            .read_to_end(&mut compressed)
+            .map_err(StegError::Compression)?;
+
+        if compressed.len() < smallest.1.len() {
+            smallest = (*compress_input, compressed);
+        }
+    }
+
+    Ok(smallest)
+}
+
+// Codec with the largest header, which is what Auto has to assume before it picks one
+pub fn largest_header_codec(compress_input: CompressInput) -> CompressInput {
+    match compress_input {
+        CompressInput::Auto => CompressInput::Zstd(19),
+        compress_input => compress_input,
+    }
+}
+
+pub fn decompress(compress_input: CompressInput, data: Vec<u8>) -> Result<Vec<u8>, StegError> {
+    let mut reader: Box<dyn Read + '_> = match compress_input {
+        CompressInput::None => return Ok(data),
+        CompressInput::Gzip => Box::new(flate2::read::GzDecoder::new(&data[..])),
+        CompressInput::Zstd(_) => {
+            Box::new(zstd::stream::read::Decoder::new(&data[..]).map_err(StegError::Compression)?)
+        }
+        CompressInput::Brotli(_) => {
+            Box::new(brotli::Decompressor::new(&data[..], BROTLI_BUFFER_SIZE))
+        }
+        CompressInput::Xz(_) => Box::new(xz2::read::XzDecoder::new(&data[..])),
+        CompressInput::Auto => unreachable!("headers never record Auto"),
+    };
+
+    let mut out = Vec::new();
+    reader
+        .read_to_end(&mut out)
+        .map_err(StegError::Compression)?;
+    Ok(out)
+}
+
+// Upper bound of the output size for n bytes of input
+fn bound(compress_input: CompressInput, n: u64) -> u64 {
+    match compress_input {
+        // Auto leaves data that does not compress as it is
+        CompressInput::None | CompressInput::Auto => n,
+        // zlib's deflateBound for raw deflate streams plus the gzip header and trailer
+        CompressInput::Gzip => n + (n >> 12) + (n >> 14) + (n >> 25) + 7 + 18,
+        CompressInput::Zstd(_) => zstd::zstd_safe::compress_bound(n as usize) as u64,
+        CompressInput::Brotli(_) => brotli::enc::BrotliEncoderMaxCompressedSize(n as usize) as u64,
+        // Uncompressed LZMA2 chunks of 64 KiB with a 3 byte header each, plus the stream and block
+        // headers, the index and the footer
+        CompressInput::Xz(_) => n + 3 * (n / 65536 + 1) + 128,
+    }
+}
+
+// Largest input whose compressed output is guaranteed to fit into the available bytes
+pub fn max_input(compress_input: CompressInput, available: u64) -> u64 {
+    let mut n = available;
+    while n > 0 && bound(compress_input, n) > available {
+        n -= (bound(compress_input, n) - available).min(n);
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_compress_decompress() {
+        let input = b"{\"level\":\"info\",\"msg\":\"request served\"}\n".repeat(50);
+
+        for compress_input in [
+            CompressInput::None,
+            CompressInput::Gzip,
+            CompressInput::Zstd(-3),
+            CompressInput::Brotli(5),
+            CompressInput::Xz(1),
+        ]
+        .iter()
+        {
+            let mut compressed = Vec::new();
+            compress(*compress_input, &input[..])
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+            if *compress_input != CompressInput::None {
+                assert!(compressed.len() < input.len() / 4);
+            }
+
+            assert_eq!(
+                input,
+                decompress(*compress_input, compressed).expect("no error")
+            );
+        }
+
+        let (compress_input, compressed) = compress_smallest(&input).unwrap();
+        assert_ne!(CompressInput::None, compress_input);
+        assert_eq!(input, decompress(compress_input, compressed).unwrap());
+
+        // A couple of bytes only grow
+        let (compress_input, compressed) = compress_smallest(b"hi").unwrap();
+        assert_eq!(CompressInput::None, compress_input);
+        assert_eq!(b"hi".to_vec(), compressed);
+
+        assert!(decompress(CompressInput::Xz(6), b"not xz".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_bound() {
+        let mut rng = ChaCha20Rng::from_seed([5; 32]);
+        let mut input = vec![0; 200_000];
+        rng.fill_bytes(&mut input);
+
+        for compress_input in [
+            CompressInput::Gzip,
+            CompressInput::Zstd(19),
+            CompressInput::Brotli(11),
+            CompressInput::Xz(6),
+        ]
+        .iter()
+        {
+            for n in [0, 1, 1000, 70_000, 200_000].iter() {
+                let mut compressed = Vec::new();
+                compress(*compress_input, &input[..*n])
+                    .unwrap()
+                    .read_to_end(&mut compressed)
+                    .unwrap();
+                assert!(
+                    compressed.len() as u64 <= bound(*compress_input, *n as u64),
+                    "{:?} of {} bytes",
+                    compress_input,
+                    n
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(CompressInput::Zstd(22), clamp(CompressInput::Zstd(100)));
+        assert_eq!(
+            CompressInput::Zstd(-131072),
+            clamp(CompressInput::Zstd(i32::MIN))
+        );
+        assert_eq!(CompressInput::Brotli(11), clamp(CompressInput::Brotli(12)));
+        assert_eq!(CompressInput::Xz(9), clamp(CompressInput::Xz(9)));
+        assert_eq!(CompressInput::Auto, clamp(CompressInput::Auto));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::error::StegError;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::convert::TryInto;
+
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32;
+// Size of the Poly1305 authentication tag appended to the ciphertext
+pub const TAG_LENGTH: usize = 16;
+
+// Argon2id costs the encoder writes. The header comes from an untrusted image, so the decoder
+// rejects anything costlier rather than spend that much memory and time before the passphrase is
+// even checked
+const M_COST: u32 = 19 * 1024;
+const T_COST: u32 = 2;
+const P_COST: u32 = 1;
+
+const KDF_ARGON2ID: u8 = 1;
+const CIPHER_CHACHA20_POLY1305: u8 = 1;
+
+// kdf + cipher + m_cost + t_cost + p_cost + salt + nonce
+const PARAMS_LENGTH: usize = 1 + 1 + 4 + 4 + 4 + SALT_LENGTH + NONCE_LENGTH;
+
+// Everything needed (besides the passphrase) to derive the key and open the payload.
+// The payload is sealed with ChaCha20-Poly1305 using a key derived from the passphrase with Argon2id
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EncryptionParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: [u8; SALT_LENGTH],
+    nonce: [u8; NONCE_LENGTH],
+}
+
+impl EncryptionParams {
+    pub(crate) fn generate() -> Self {
+        let mut salt = [0; SALT_LENGTH];
+        let mut nonce = [0; NONCE_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        Self {
+            m_cost: M_COST,
+            t_cost: T_COST,
+            p_cost: P_COST,
+            salt,
+            nonce,
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(PARAMS_LENGTH);
+        out.push(KDF_ARGON2ID);
+        out.push(CIPHER_CHACHA20_POLY1305);
+        out.extend_from_slice(&self.m_cost.to_be_bytes());
+        out.extend_from_slice(&self.t_cost.to_be_bytes());
+        out.extend_from_slice(&self.p_cost.to_be_bytes());
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.nonce);
+        out
+    }
+
+    pub(crate) fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() != PARAMS_LENGTH {
+            return Err("invalid encryption parameters length".to_string());
+        }
+
+        if data[0] != KDF_ARGON2ID {
+            return Err(format!(
+                "unsupported key derivation function: {:#x}",
+                data[0]
+            ));
+        }
+
+        if data[1] != CIPHER_CHACHA20_POLY1305 {
+            return Err(format!("unsupported cipher: {:#x}", data[1]));
+        }
+
+        let params = Self {
+            m_cost: u32::from_be_bytes(data[2..6].try_into().unwrap()),
+            t_cost: u32::from_be_bytes(data[6..10].try_into().unwrap()),
+            p_cost: u32::from_be_bytes(data[10..14].try_into().unwrap()),
+            salt: data[14..30].try_into().unwrap(),
+            nonce: data[30..42].try_into().unwrap(),
+        };
+
+        if params.m_cost > M_COST || params.t_cost > T_COST || params.p_cost > P_COST {
+            return Err(format!(
+                "key derivation costs exceed the supported maximum: m_cost {}, t_cost {}, \
+                 p_cost {}",
+                params.m_cost, params.t_cost, params.p_cost
+            ));
+        }
+
+        Ok(params)
+    }
+
+    fn derive_cipher(&self, passphrase: &str) -> Result<ChaCha20Poly1305, StegError> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LENGTH)).map_err(
+            |err| StegError::Crypto(format!("invalid key derivation parameters: {}", err)),
+        )?;
+
+        let mut key = [0; KEY_LENGTH];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|err| StegError::Crypto(format!("key derivation failure: {}", err)))?;
+
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+// The authentication tag covers the associated data as well, so that it can not be changed
+// without decryption failing
+pub(crate) fn encrypt(
+    passphrase: &str,
+    params: &EncryptionParams,
+    associated_data: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, StegError> {
+    let cipher = params.derive_cipher(passphrase)?;
+    let payload = Payload {
+        msg: plaintext,
+        aad: associated_data,
+    };
+
+    cipher
+        .encrypt(Nonce::from_slice(&params.nonce), payload)
+        .map_err(|_| StegError::Crypto("encryption failure".to_string()))
+}
+
+pub(crate) fn decrypt(
+    passphrase: &str,
+    params: &EncryptionParams,
+    associated_data: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, StegError> {
+    let cipher = params.derive_cipher(passphrase)?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: associated_data,
+    };
+
+    cipher
+        .decrypt(Nonce::from_slice(&params.nonce), payload)
+        .map_err(|_| StegError::WrongPassphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let params = EncryptionParams::generate();
+        let ciphertext = encrypt("secret", &params, b"header", b"Hey!").expect("no error");
+        assert_ne!(b"Hey!".to_vec(), ciphertext);
+
+        let plaintext = decrypt("secret", &params, b"header", &ciphertext).expect("no error");
+        assert_eq!(b"Hey!".to_vec(), plaintext);
+    }
+
+    #[test]
+    fn test_decrypt_wrong_passphrase() {
+        let params = EncryptionParams::generate();
+        let ciphertext = encrypt("secret", &params, b"header", b"Hey!").expect("no error");
+
+        let err = decrypt("not secret", &params, b"header", &ciphertext).unwrap_err();
+        assert!(matches!(err, StegError::WrongPassphrase));
+    }
+
+    #[test]
+    fn test_decrypt_changed_associated_data() {
+        let params = EncryptionParams::generate();
+        let ciphertext = encrypt("secret", &params, b"header", b"Hey!").expect("no error");
+
+        let err = decrypt("secret", &params, b"HEADER", &ciphertext).unwrap_err();
+        assert!(matches!(err, StegError::WrongPassphrase));
+    }
+
+    #[test]
+    fn test_params_round_trip() {
+        let params = EncryptionParams::generate();
+        let decoded = EncryptionParams::from_bytes(&params.to_bytes()).expect("no error");
+        assert_eq!(params, decoded);
+    }
+
+    #[test]
+    fn test_params_above_maximum_costs() {
+        let params = EncryptionParams::generate().to_bytes();
+
+        for (offset, cost) in [(2, M_COST), (6, T_COST), (10, P_COST)] {
+            let mut costly = params.clone();
+            costly[offset..offset + 4].copy_from_slice(&(cost + 1).to_be_bytes());
+            assert!(EncryptionParams::from_bytes(&costly).is_err());
+        }
+
+        let mut costly = params;
+        costly[2..6].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(EncryptionParams::from_bytes(&costly).is_err());
+    }
+}
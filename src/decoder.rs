@@ -1,14 +1,28 @@
+use crate::byte_encodings::Sample;
+use crate::error::StegError;
+use crate::layout::{self, Layout};
+use crate::metadata::Metadata;
 use crate::{
-    byte_encodings, err_to_io_error, ByteSplitGranularity, CompressInput, DataHeader, HEADER_LENGTH,
+    byte_encodings, compression, crypto, digest, fec, format, scatter, shamir, DataHeader, Share,
+    EXTENSIONS_LENGTH, HEADER_LENGTH,
 };
-use flate2::write::GzDecoder;
+use image::{ColorType, DynamicImage, ImageFormat};
 use log::debug;
-use std::collections::hash_map::DefaultHasher;
-use std::convert::TryFrom;
-use std::hash::Hasher;
 use std::io::{BufRead, Read, Seek, Write};
 
-pub struct Decoder {}
+// What was recovered from an image besides the data itself
+#[derive(Debug, Clone, Default)]
+pub struct DecodeReport {
+    pub metadata: Metadata,
+    // Number of corrupted bytes that were repaired by error correction
+    pub corrected_symbols: usize,
+}
+
+pub struct Decoder {
+    input_format: Option<ImageFormat>,
+    passphrase: Option<String>,
+    scatter_key: Option<String>,
+}
 
 impl Default for Decoder {
     fn default() -> Self {
@@ -18,113 +32,503 @@ impl Default for Decoder {
 
 impl Decoder {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            input_format: None,
+            passphrase: None,
+            scatter_key: None,
+        }
+    }
+
+    // Format to read images in when it can not be told from their content, which is the case for
+    // TGA images. Without it such images are refused as an unsupported format
+    pub fn with_input_format(mut self, format: ImageFormat) -> Self {
+        self.input_format = Some(format);
+        self
+    }
+
+    // Passphrase used to open payloads that were encrypted by the encoder
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    // Key the encoder used to scatter the header and data across the image
+    pub fn with_scatter_key(mut self, key: &str) -> Self {
+        self.scatter_key = Some(key.to_string());
+        self
     }
 
     pub fn decode<R: BufRead + Read + Seek, W: Write>(
         &self,
         input_image: &mut R,
         output: &mut W,
-    ) -> Result<(), std::io::Error> {
-        match image::load(input_image, image::ImageFormat::Png) {
-            Ok(img) => {
-                let image_bytes = img
-                    .to_rgba8()
-                    .bytes()
-                    .collect::<Result<Vec<u8>, std::io::Error>>()?;
-
-                let payload = self.uncover_from(image_bytes)?;
-                output.write_all(&payload)
-            }
-            Err(err) => Err(err_to_io_error(err)),
+    ) -> Result<(), StegError> {
+        self.decode_with_metadata(input_image, output)?;
+        Ok(())
+    }
+
+    // Decodes the data along with the information about the file it came from, images encoded
+    // without metadata return an empty Metadata
+    pub fn decode_with_metadata<R: BufRead + Read + Seek, W: Write>(
+        &self,
+        input_image: &mut R,
+        output: &mut W,
+    ) -> Result<Metadata, StegError> {
+        Ok(self.decode_with_report(input_image, output)?.metadata)
+    }
+
+    // Decodes the data, also reporting how many corrupted bytes error correction repaired
+    pub fn decode_with_report<R: BufRead + Read + Seek, W: Write>(
+        &self,
+        input_image: &mut R,
+        output: &mut W,
+    ) -> Result<DecodeReport, StegError> {
+        let (payload, report) =
+            self.decode_image_with_report(&format::read(input_image, self.input_format)?.0)?;
+        output.write_all(&payload)?;
+        Ok(report)
+    }
+
+    // Extracts the data from an image that is already decoded
+    pub fn decode_image(&self, image: &DynamicImage) -> Result<Vec<u8>, StegError> {
+        Ok(self.decode_image_with_report(image)?.0)
+    }
+
+    // Same as decode_image, also returning the metadata and the repaired bytes
+    pub fn decode_image_with_report(
+        &self,
+        image: &DynamicImage,
+    ) -> Result<(Vec<u8>, DecodeReport), StegError> {
+        self.reassemble(std::iter::once(image))
+    }
+
+    // Puts back together a payload that the encoder split across several images, which can be
+    // given in any order. Images that carry shares are combined as decode_shares does
+    pub fn decode_images(&self, images: &[DynamicImage]) -> Result<Vec<u8>, StegError> {
+        Ok(self.decode_images_with_report(images)?.0)
+    }
+
+    // Same as decode_images, also returning the metadata and the repaired bytes of all images
+    pub fn decode_images_with_report(
+        &self,
+        images: &[DynamicImage],
+    ) -> Result<(Vec<u8>, DecodeReport), StegError> {
+        self.reassemble(images)
+    }
+
+    // Reconstructs a payload that the encoder split into shares from at least as many of the
+    // images as its threshold, in any order
+    pub fn decode_shares(&self, images: &[DynamicImage]) -> Result<Vec<u8>, StegError> {
+        Ok(self.decode_shares_with_report(images)?.0)
+    }
+
+    // Same as decode_shares, also returning the repaired bytes of all images
+    pub fn decode_shares_with_report(
+        &self,
+        images: &[DynamicImage],
+    ) -> Result<(Vec<u8>, DecodeReport), StegError> {
+        self.reassemble(images)
+    }
+
+    // Reads the header of the payload without extracting the data, returns None if the image
+    // does not carry a payload
+    pub fn inspect<R: BufRead + Read + Seek>(
+        &self,
+        input_image: &mut R,
+    ) -> Result<Option<DataHeader>, StegError> {
+        self.inspect_image(&format::read(input_image, self.input_format)?.0)
+    }
+
+    // Same as inspect for an image that is already decoded
+    pub fn inspect_image(&self, image: &DynamicImage) -> Result<Option<DataHeader>, StegError> {
+        let image_bytes = image_bytes(image);
+        let seed = self.seed()?;
+
+        let has_magic = layout::candidates(carrier_colour_type(image))
+            .into_iter()
+            .any(|(mask, transparent_pixels)| {
+                let layout = Layout::of_image(image, mask, transparent_pixels);
+                DataHeader::has_magic(
+                    EmbeddedBytes::new(&image_bytes, layout, seed).gather(HEADER_LENGTH),
+                )
+            });
+        if !has_magic {
+            return Ok(None);
         }
+
+        let (header, _, _) = self.locate_header(image, &image_bytes, seed)?;
+        Ok(Some(header))
     }
 
-    fn uncover_from(&self, input: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
-        if input.len() < HEADER_LENGTH {
-            return Err(err_to_io_error(
-                "validation failure: image header is not present",
-            ));
+    fn seed(&self) -> Result<Option<scatter::Seed>, StegError> {
+        match &self.scatter_key {
+            Some(key) => Ok(Some(scatter::derive_seed(key)?)),
+            None => Ok(None),
         }
+    }
 
-        // 1. extract header
-        let header = match self.extract_header(&input[0..HEADER_LENGTH]) {
-            Ok(h) => Ok(h),
-            Err(err) => Err(err_to_io_error(err)),
-        }?;
+    // The header is embedded in the same samples as the data, which only the header tells. Every
+    // layout the image could carry data in is tried, starting with the default one, and the
+    // header has to name the layout it was found in. The error of the first layout whose magic
+    // matched is returned if none works out, or the error of the default layout
+    fn locate_header<'a>(
+        &self,
+        img: &DynamicImage,
+        input: &'a [u8],
+        seed: Option<scatter::Seed>,
+    ) -> Result<(DataHeader, usize, EmbeddedBytes<'a>), StegError> {
+        let color_type = carrier_colour_type(img);
+        let mut default_error = None;
+        let mut magic_error = None;
+
+        for (i, (mask, transparent_pixels)) in
+            layout::candidates(color_type).into_iter().enumerate()
+        {
+            let mut embedded =
+                EmbeddedBytes::new(input, Layout::of_image(img, mask, transparent_pixels), seed);
+            let has_magic = DataHeader::has_magic(embedded.gather(HEADER_LENGTH));
+            if i > 0 && !has_magic {
+                continue;
+            }
+
+            let err = match self.extract_header(&mut embedded) {
+                Ok((header, header_length))
+                    if header.transparent_pixels == transparent_pixels
+                        && layout::carrier_channels(
+                            color_type,
+                            header.channel_mask,
+                            transparent_pixels,
+                        ) == layout::carrier_channels(color_type, mask, transparent_pixels) =>
+                {
+                    return Ok((header, header_length, embedded));
+                }
+                Ok(_) => StegError::InvalidHeader(
+                    "header does not match the channels it was found in".to_string(),
+                ),
+                Err(err) => err,
+            };
+
+            if has_magic && magic_error.is_none() {
+                magic_error = Some(err);
+            } else if i == 0 {
+                default_error = Some(err);
+            }
+        }
+
+        Err(magic_error
+            .or(default_error)
+            .expect("the default layout is always tried"))
+    }
+
+    fn extract_header(
+        &self,
+        embedded: &mut EmbeddedBytes,
+    ) -> Result<(DataHeader, usize), StegError> {
+        let header_length =
+            DataHeader::read_length(embedded.gather(HEADER_LENGTH + EXTENSIONS_LENGTH))?;
+        let header = DataHeader::read_from(embedded.gather(header_length))?;
 
         debug!("decoded header: {:?}", header);
+        Ok(header)
+    }
+
+    // Extracts the data the images carry and puts the payload back together from the fragments
+    // or shares of it
+    fn reassemble<'a, I: IntoIterator<Item = &'a DynamicImage>>(
+        &self,
+        images: I,
+    ) -> Result<(Vec<u8>, DecodeReport), StegError> {
+        let seed = self.seed()?;
+        let mut parts = Vec::new();
+        let mut corrected_symbols = 0;
+        for img in images {
+            let (header, data, corrected) = self.uncover_from(img, &image_bytes(img), seed)?;
+            corrected_symbols += corrected;
+            parts.push((header, data));
+        }
 
-        let minimum_size = match header.granularity {
-            ByteSplitGranularity::FourBits => (header.bytes_count as usize * 2),
-            ByteSplitGranularity::TwoBits => (header.bytes_count as usize * 4),
-            ByteSplitGranularity::OneBit => (header.bytes_count as usize * 8),
+        let (mut header, data) = match parts.first() {
+            Some((header, _)) if header.share.is_some() => combine_shares(parts)?,
+            _ => join_fragments(parts)?,
         };
 
-        let remaining = &input[HEADER_LENGTH..];
+        let payload = self.open_payload(&mut header, data)?;
+        Ok((
+            payload,
+            DecodeReport {
+                metadata: header.metadata,
+                corrected_symbols,
+            },
+        ))
+    }
+
+    // Returns the header, the data as it was embedded and the number of bytes error correction
+    // repaired
+    fn uncover_from(
+        &self,
+        img: &DynamicImage,
+        input: &[u8],
+        seed: Option<scatter::Seed>,
+    ) -> Result<(DataHeader, Vec<u8>, usize), StegError> {
+        // 1. extract header
+        let (header, header_length, mut embedded) = self.locate_header(img, input, seed)?;
+
+        let minimum_size =
+            (header.bytes_count as usize).saturating_mul(header.granularity.parts_per_byte());
+
+        let remaining =
+            &embedded.gather(header_length.saturating_add(minimum_size))[header_length..];
 
         if remaining.len() < minimum_size {
-            return Err(err_to_io_error(
-                "validation failure: image data is too small/does not match bytes count in header",
-            ));
+            return Err(StegError::TruncatedData {
+                needed: minimum_size,
+                available: remaining.len(),
+            });
         }
 
+        // 2. extract data
         let mut data = Vec::new();
-        let mut hasher = DefaultHasher::new();
-
-        if header.compress_input == CompressInput::Gzip {
-            let mut gzip_decoder = GzDecoder::new(data);
-
-            self.decode_data(remaining, &header, |byte| {
-                hasher.write_u8(byte);
-                gzip_decoder.write(&[byte])?;
-                Ok(())
-            })?;
-
-            data = gzip_decoder.finish()?;
-        } else {
-            self.decode_data(remaining, &header, |byte| {
-                hasher.write_u8(byte);
-                data.push(byte);
-                Ok(())
-            })?;
+
+        self.decode_data(remaining, &header, |byte| {
+            data.push(byte);
+            Ok(())
+        })?;
+
+        // 3. repair, the hash covers the parity bytes as well
+        let corrected_symbols = match header.error_correction {
+            Some(ec) => fec::correct(ec, &mut data)?,
+            None => 0,
+        };
+        if corrected_symbols > 0 {
+            debug!("corrected {} corrupted bytes", corrected_symbols);
         }
 
-        // 3. validate
-        let hash = hasher.finish();
-        if hash != header.data_hash {
-            return Err(err_to_io_error(format!(
-                "validation failure: data hash {} does not match hash printed in header {}",
-                hash, header.data_hash
-            )));
+        // 4. validate
+        let mut hasher = digest::DataHasher::for_header(&header);
+        hasher.update(&data);
+        hasher.verify(&header)?;
+
+        if let Some(ec) = header.error_correction {
+            data = fec::strip_parity(ec, &data);
         }
 
-        Ok(data)
+        Ok((header, data, corrected_symbols))
     }
 
-    fn extract_header(&self, input: &[u8]) -> Result<DataHeader, String> {
-        let mut raw_header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
-        raw_header[..HEADER_LENGTH].copy_from_slice(&input[..]);
-        raw_header.iter_mut().for_each(|x| *x &= 0x0F);
-        DataHeader::try_from(raw_header)
+    // Decrypts and decompresses the data of all fragments. The metadata sealed along with an
+    // encrypted payload goes into the header
+    fn open_payload(
+        &self,
+        header: &mut DataHeader,
+        mut data: Vec<u8>,
+    ) -> Result<Vec<u8>, StegError> {
+        // 5. decrypt
+        if let Some(params) = &header.encryption {
+            let passphrase = match &self.passphrase {
+                Some(p) => Ok(p),
+                None => Err(StegError::PassphraseRequired),
+            }?;
+
+            data = crypto::decrypt(passphrase, params, &header.associated_data()?, &data)?;
+            if header.version() >= 0x4 {
+                let (metadata, length) = crate::open_metadata(&data)?;
+                header.metadata = metadata;
+                data.drain(..length);
+            }
+        }
+
+        // 6. decompress
+        compression::decompress(header.compress_input, data)
     }
 
-    fn decode_data<F: FnMut(u8) -> Result<(), std::io::Error>>(
+    fn decode_data<F: FnMut(u8) -> Result<(), StegError>>(
         &self,
         data: &[u8],
         header: &DataHeader,
         handle_byte_fn: F,
-    ) -> Result<(), std::io::Error> {
-        let chunk_size: usize = match header.granularity {
-            ByteSplitGranularity::FourBits => 2,
-            ByteSplitGranularity::TwoBits => 4,
-            ByteSplitGranularity::OneBit => 8,
-        };
-
-        data.chunks(chunk_size)
+    ) -> Result<(), StegError> {
+        data.chunks(header.granularity.parts_per_byte())
             .take(header.bytes_count as usize)
             .map(|chunk| byte_encodings::merge_bytes(header.granularity, chunk))
-            .map(handle_byte_fn)
-            .collect()
+            .try_for_each(handle_byte_fn)
+    }
+}
+
+// The container format is detected from the image itself, TGA images are not recognised. Readers
+// that can not seek are read into memory first, the decoder reads its seekable input directly
+// with format::read
+pub(crate) fn load_image<R: Read>(input_image: &mut R) -> Result<DynamicImage, StegError> {
+    let mut bytes = Vec::new();
+    input_image.read_to_end(&mut bytes)?;
+    Ok(format::load(&bytes, None)?.0)
+}
+
+// The low byte of every sample of the image, which is where the encoder hid the data. The
+// samples are read in the image's own layout, which is the layout the encoder embedded into
+pub(crate) fn image_bytes(img: &DynamicImage) -> Vec<u8> {
+    match img {
+        DynamicImage::ImageLuma16(buf) => low_bytes(buf),
+        DynamicImage::ImageLumaA16(buf) => low_bytes(buf),
+        DynamicImage::ImageRgb16(buf) => low_bytes(buf),
+        DynamicImage::ImageRgba16(buf) => low_bytes(buf),
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => img.as_bytes().to_vec(),
+        _ => img.to_rgba8().into_raw(),
+    }
+}
+
+// Colour type of the samples image_bytes reads
+fn carrier_colour_type(img: &DynamicImage) -> ColorType {
+    match img.color() {
+        color_type @ (ColorType::L8
+        | ColorType::La8
+        | ColorType::Rgb8
+        | ColorType::Rgba8
+        | ColorType::L16
+        | ColorType::La16
+        | ColorType::Rgb16
+        | ColorType::Rgba16) => color_type,
+        _ => ColorType::Rgba8,
+    }
+}
+
+// Joins the fragments in order. An image whose header has no fragment carries the whole payload,
+// as the only fragment. Fragments that were given twice are only used once, all fragments that
+// were not given are reported
+fn join_fragments(parts: Vec<(DataHeader, Vec<u8>)>) -> Result<(DataHeader, Vec<u8>), StegError> {
+    let mut fragments: Vec<Option<(DataHeader, Vec<u8>)>> = Vec::new();
+    let mut message: Option<Option<(u64, usize)>> = None;
+
+    for (header, data) in parts {
+        if header.share.is_some() {
+            return Err(StegError::MixedShares);
+        }
+
+        let fragment = header.fragment.map(|f| (f.message_id(), f.count()));
+        let (index, count) = header.fragment.map_or((0, 1), |f| (f.index(), f.count()));
+        match message {
+            Some(message) if message != fragment => return Err(StegError::MixedFragments),
+            Some(_) => {}
+            None => {
+                message = Some(fragment);
+                fragments.resize(count, None);
+            }
+        }
+
+        match &fragments[index] {
+            Some((_, known)) if *known != data => return Err(StegError::MixedFragments),
+            Some(_) => {}
+            None => fragments[index] = Some((header, data)),
+        }
+    }
+
+    // Without any image, not even the count of fragments is known
+    if fragments.is_empty() {
+        return Err(StegError::MissingFragments {
+            missing: vec![0],
+            count: 1,
+        });
+    }
+
+    let missing: Vec<usize> = fragments
+        .iter()
+        .enumerate()
+        .filter(|(_, fragment)| fragment.is_none())
+        .map(|(index, _)| index)
+        .collect();
+    if !missing.is_empty() {
+        return Err(StegError::MissingFragments {
+            missing,
+            count: fragments.len(),
+        });
+    }
+
+    let mut fragments = fragments.into_iter().flatten();
+    let (header, mut data) = fragments.next().expect("no fragment is missing");
+    for (_, fragment) in fragments {
+        data.extend_from_slice(&fragment);
+    }
+    Ok((header, data))
+}
+
+// Reconstructs the payload from the first threshold distinct shares. Shares that were given twice
+// are only used once
+fn combine_shares(parts: Vec<(DataHeader, Vec<u8>)>) -> Result<(DataHeader, Vec<u8>), StegError> {
+    let mut shares: Vec<(Share, DataHeader, Vec<u8>)> = Vec::new();
+    for (header, data) in parts {
+        let share = header.share.ok_or(StegError::MixedShares)?;
+        if let Some((first, _, first_data)) = shares.first() {
+            if (
+                first.secret_id(),
+                first.threshold(),
+                first.count(),
+                first_data.len(),
+            ) != (
+                share.secret_id(),
+                share.threshold(),
+                share.count(),
+                data.len(),
+            ) {
+                return Err(StegError::MixedShares);
+            }
+        }
+
+        match shares
+            .iter()
+            .find(|(known, _, _)| known.index() == share.index())
+        {
+            Some((_, _, known)) if *known != data => return Err(StegError::MixedShares),
+            Some(_) => {}
+            None => shares.push((share, header, data)),
+        }
+    }
+
+    let threshold = shares[0].0.threshold();
+    if shares.len() < threshold {
+        return Err(StegError::MissingShares {
+            given: shares.len(),
+            threshold,
+        });
+    }
+
+    let given: Vec<(usize, &[u8])> = shares[..threshold]
+        .iter()
+        .map(|(share, _, data)| (share.index(), &data[..]))
+        .collect();
+    let data = shamir::combine(&given);
+    Ok((shares.swap_remove(0).1, data))
+}
+
+fn low_bytes(samples: &[u16]) -> Vec<u8> {
+    samples.iter().map(|sample| sample.low_byte()).collect()
+}
+
+// The bytes of the image that carry the header and data, gathered in the order they were written
+struct EmbeddedBytes<'a> {
+    input: &'a [u8],
+    positions: Box<dyn Iterator<Item = usize>>,
+    bytes: Vec<u8>,
+}
+
+impl<'a> EmbeddedBytes<'a> {
+    fn new(input: &'a [u8], layout: Layout, seed: Option<scatter::Seed>) -> Self {
+        Self {
+            input,
+            positions: Box::new(
+                scatter::positions(layout.len(), seed).map(move |p| layout.sample(p)),
+            ),
+            bytes: Vec::new(),
+        }
+    }
+
+    // Returns (at most) the first count embedded bytes
+    fn gather(&mut self, count: usize) -> &[u8] {
+        let needed = count.saturating_sub(self.bytes.len());
+        let input = self.input;
+        self.bytes
+            .extend(self.positions.by_ref().take(needed).map(|p| input[p]));
+        &self.bytes[..count.min(self.bytes.len())]
     }
 }
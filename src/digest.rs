@@ -0,0 +1,177 @@
+use crate::error::StegError;
+use crate::{DataHeader, DigestKind};
+use sha2::Digest as _;
+use std::collections::hash_map::DefaultHasher;
+use std::convert::{TryFrom, TryInto};
+use std::hash::Hasher;
+
+pub const DIGEST_LENGTH: usize = 32;
+
+// Strong digest of the embedded bytes, stored in the header next to the CRC32C data hash
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Digest {
+    pub kind: DigestKind,
+    pub value: [u8; DIGEST_LENGTH],
+}
+
+impl Digest {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + DIGEST_LENGTH);
+        out.push(self.kind.into());
+        out.extend_from_slice(&self.value);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() != 1 + DIGEST_LENGTH {
+            return Err("invalid digest length".to_string());
+        }
+
+        Ok(Self {
+            kind: DigestKind::try_from(data[0])?,
+            value: data[1..].try_into().unwrap(),
+        })
+    }
+}
+
+enum StrongHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+// Hashes the embedded bytes the way the header version expects. Headers before version 3 hold
+// a DefaultHasher hash, whose algorithm is unspecified, later ones hold a CRC32C checksum and
+// optionally a strong digest
+pub struct DataHasher {
+    version: u8,
+    legacy: DefaultHasher,
+    crc: u32,
+    strong: Option<StrongHasher>,
+}
+
+impl DataHasher {
+    pub fn new(version: u8, kind: DigestKind) -> Self {
+        let strong = match kind {
+            DigestKind::Crc32c => None,
+            DigestKind::Blake3 => Some(StrongHasher::Blake3(Box::new(blake3::Hasher::new()))),
+            DigestKind::Sha256 => Some(StrongHasher::Sha256(sha2::Sha256::new())),
+        };
+
+        Self {
+            version,
+            legacy: DefaultHasher::new(),
+            crc: 0,
+            strong,
+        }
+    }
+
+    pub fn for_header(header: &DataHeader) -> Self {
+        let kind = header
+            .digest
+            .map_or(DigestKind::Crc32c, |digest| digest.kind);
+        Self::new(header.version, kind)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if self.version < 0x3 {
+            data.iter().for_each(|b| self.legacy.write_u8(*b));
+            return;
+        }
+
+        self.crc = crc32c::crc32c_append(self.crc, data);
+
+        match &mut self.strong {
+            Some(StrongHasher::Blake3(hasher)) => {
+                hasher.update(data);
+            }
+            Some(StrongHasher::Sha256(hasher)) => hasher.update(data),
+            None => {}
+        }
+    }
+
+    // Returns the data hash and, if one was requested, the strong digest
+    pub fn finish(self) -> (u64, Option<Digest>) {
+        if self.version < 0x3 {
+            return (self.legacy.finish(), None);
+        }
+
+        let digest = self.strong.map(|strong| match strong {
+            StrongHasher::Blake3(hasher) => Digest {
+                kind: DigestKind::Blake3,
+                value: *hasher.finalize().as_bytes(),
+            },
+            StrongHasher::Sha256(hasher) => Digest {
+                kind: DigestKind::Sha256,
+                value: hasher.finalize().into(),
+            },
+        });
+
+        (self.crc as u64, digest)
+    }
+
+    pub fn verify(self, header: &DataHeader) -> Result<(), StegError> {
+        let (hash, digest) = self.finish();
+
+        if hash != header.data_hash {
+            return Err(StegError::HashMismatch {
+                expected: header.data_hash,
+                actual: hash,
+            });
+        }
+
+        match header.digest {
+            Some(expected) if digest != Some(expected) => {
+                Err(StegError::DigestMismatch(expected.kind))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        let mut hasher = DataHasher::new(0x3, DigestKind::Crc32c);
+        hasher.update(b"123456789");
+        assert_eq!((0xE3069283, None), hasher.finish());
+    }
+
+    #[test]
+    fn test_sha256() {
+        let mut hasher = DataHasher::new(0x3, DigestKind::Sha256);
+        hasher.update(b"abc");
+
+        let (_, digest) = hasher.finish();
+        assert_eq!(
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ],
+            digest.unwrap().value
+        );
+    }
+
+    #[test]
+    fn test_incremental_update() {
+        let mut whole = DataHasher::new(0x3, DigestKind::Blake3);
+        whole.update(b"Hey!");
+
+        let mut bytewise = DataHasher::new(0x3, DigestKind::Blake3);
+        b"Hey!".iter().for_each(|b| bytewise.update(&[*b]));
+
+        assert_eq!(whole.finish(), bytewise.finish());
+    }
+
+    #[test]
+    fn test_digest_round_trip() {
+        let digest = Digest {
+            kind: DigestKind::Blake3,
+            value: [0xAB; DIGEST_LENGTH],
+        };
+        assert_eq!(digest, Digest::from_bytes(&digest.to_bytes()).unwrap());
+    }
+}
@@ -1,89 +1,646 @@
+use crate::error::StegError;
+use crate::layout::{self, Layout};
+use crate::metadata::Metadata;
+use crate::quality::QualityReport;
 use crate::*;
-use flate2::read::GzEncoder;
-use flate2::Compression;
+use image::{ColorType, DynamicImage, GenericImageView, ImageFormat};
 use log::debug;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
-use std::io::{BufRead, Cursor, Read, Seek, Write};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::io::{BufRead, Cursor, ErrorKind, Read, Seek, Write};
 
-struct EncodeOutput {
-    header: Vec<u8>,
-    data: Vec<u8>,
-}
-
-impl EncodeOutput {
-    fn len(&self) -> usize {
-        self.header.len() + self.data.len()
-    }
-}
+// Size of the chunks the data is read in while it is embedded
+const CHUNK_SIZE: usize = 8192;
 
 pub struct Encoder {
     compress_input: CompressInput,
     byte_split_level: ByteSplitGranularity,
+    auto_granularity: bool,
+    native_colour_type: bool,
+    ancillary_chunks: bool,
+    cover_format: Option<ImageFormat>,
+    output_format: ImageFormat,
+    digest_kind: DigestKind,
+    error_correction: Option<ErrorCorrection>,
+    embedding_mode: EmbeddingMode,
+    channel_mask: ChannelMask,
+    transparent_pixels: bool,
+    min_psnr: Option<f64>,
+    passphrase: Option<String>,
+    scatter_key: Option<String>,
 }
 
 impl Encoder {
     pub fn new(compress_input: CompressInput, byte_split_level: ByteSplitGranularity) -> Self {
         Self {
-            compress_input,
+            compress_input: compression::clamp(compress_input),
             byte_split_level,
+            auto_granularity: false,
+            native_colour_type: false,
+            ancillary_chunks: false,
+            cover_format: None,
+            output_format: ImageFormat::Png,
+            digest_kind: DigestKind::Crc32c,
+            error_correction: None,
+            embedding_mode: EmbeddingMode::Replacement,
+            channel_mask: ChannelMask::ALL,
+            transparent_pixels: false,
+            min_psnr: None,
+            passphrase: None,
+            scatter_key: None,
         }
     }
 
+    // Pick the least invasive granularity that still fits the data into the cover image, using the
+    // granularity given to new as the most invasive one allowed
+    pub fn with_auto_granularity(mut self) -> Self {
+        self.auto_granularity = true;
+        self
+    }
+
+    // Embed into the channels of the cover image as they are and write the output with the same
+    // colour type, rather than converting it to RGBA. Grayscale and RGB covers carry less data
+    // this way, as they have fewer samples per pixel
+    pub fn with_native_colour_type(mut self) -> Self {
+        self.native_colour_type = true;
+        self
+    }
+
+    // Copy the ancillary chunks of the cover image (colour profile, physical dimensions, text,
+    // timestamps, ...) into the output, which otherwise only holds the image data. Only applies
+    // when both the cover and the output are PNG images
+    pub fn with_ancillary_chunks(mut self) -> Self {
+        self.ancillary_chunks = true;
+        self
+    }
+
+    // Format to read the cover image in when it can not be told from its content, which is the
+    // case for TGA images. Without it such covers are refused as an unsupported format
+    pub fn with_cover_format(mut self, format: ImageFormat) -> Self {
+        self.cover_format = Some(format);
+        self
+    }
+
+    // Format the output image is written in, defaults to PNG. Only lossless formats can carry the
+    // data: PNG, BMP, TIFF, TGA, PNM, QOI and WebP (which is written losslessly)
+    pub fn with_output_format(mut self, format: ImageFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    // Integrity check stored in the header, defaults to a CRC32C checksum
+    pub fn with_digest(mut self, digest_kind: DigestKind) -> Self {
+        self.digest_kind = digest_kind;
+        self
+    }
+
+    // How the low bits of the cover image are changed, defaults to replacing them. Matching is
+    // harder to detect, the decoder reads both the same way
+    pub fn with_embedding_mode(mut self, mode: EmbeddingMode) -> Self {
+        self.embedding_mode = mode;
+        self
+    }
+
+    // Channels of the cover image that carry the header and data, defaults to all of them. Leaving
+    // out the alpha channel keeps an opaque image's alpha constant, the decoder finds the channels
+    // on its own
+    pub fn with_channel_mask(mut self, mask: ChannelMask) -> Self {
+        self.channel_mask = mask;
+        self
+    }
+
+    // Only hide the header and data in the colour channels of fully transparent pixels, which
+    // leaves every opaque pixel untouched. The changes do not show when the image is displayed but
+    // are plain in its samples. The cover image needs an alpha channel and enough transparent
+    // pixels to carry the header and data
+    pub fn with_transparent_pixels(mut self) -> Self {
+        self.transparent_pixels = true;
+        self
+    }
+
+    // Add Reed-Solomon parity bytes to the embedded data, so that it can be recovered when some of
+    // its bytes get corrupted. redundancy is the share of parity bytes in the embedded data, e.g.
+    // 0.1 repairs up to 12 corrupted bytes in every 255 byte block. Only the data is protected,
+    // the header and its extension records are not, so corrupting any of the samples that carry
+    // them still makes decoding fail
+    pub fn with_error_correction(mut self, redundancy: f64) -> Self {
+        self.error_correction = Some(ErrorCorrection::new(redundancy));
+        self
+    }
+
+    // Refuse to write output images whose PSNR, compared with the cover image, is below the given
+    // number of dB
+    pub fn with_min_psnr(mut self, min_psnr: f64) -> Self {
+        self.min_psnr = Some(min_psnr);
+        self
+    }
+
+    // Encrypt the (optionally compressed) input with a key derived from the passphrase
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    // Scatter the header and data across the cover image in an order derived from the key, rather
+    // than writing them sequentially from the first pixel
+    pub fn with_scatter_key(mut self, key: &str) -> Self {
+        self.scatter_key = Some(key.to_string());
+        self
+    }
+
     pub fn encode<R1: BufRead + Read + Seek, R2: Read, W: Write>(
         &self,
         cover_image: R1,
         input_data: &mut R2,
         output: &mut W,
-    ) -> Result<(), std::io::Error> {
-        match image::load(cover_image, image::ImageFormat::Png) {
-            Ok(img) => {
-                let encode_output = if let CompressInput::Gzip = self.compress_input {
-                    let compressed = self.compress(input_data)?;
-                    self.encode_data(&mut Cursor::new(compressed))
-                } else {
-                    self.encode_data(input_data)
-                }?;
-
-                let rgba8 = img.to_rgba8();
-
-                let mut cover_image_bytes: Vec<u8> =
-                    rgba8.bytes().collect::<Result<Vec<u8>, std::io::Error>>()?;
-
-                self.check_utilisation(&cover_image_bytes, &encode_output)?;
-
-                self.merge_into(&mut cover_image_bytes, encode_output);
-
-                let out_buffer = match image::RgbaImage::from_raw(
-                    rgba8.width(),
-                    rgba8.height(),
-                    cover_image_bytes,
-                ) {
-                    Some(b) => Ok(b),
-                    None => Err(err_to_io_error(
-                        "could not create output image buffer from raw parts",
-                    )),
-                }?;
-
-                match image::DynamicImage::ImageRgba8(out_buffer)
-                    .write_to(output, image::ImageFormat::Png)
-                {
-                    Ok(_) => Ok(()),
-                    Err(err) => Err(err_to_io_error(err)),
-                }
+    ) -> Result<(), StegError> {
+        self.encode_with_metadata(cover_image, input_data, &Metadata::default(), output)
+    }
+
+    // Encodes the input data along with information about the file it came from
+    pub fn encode_with_metadata<R1: BufRead + Read + Seek, R2: Read, W: Write>(
+        &self,
+        cover_image: R1,
+        input_data: &mut R2,
+        metadata: &Metadata,
+        output: &mut W,
+    ) -> Result<(), StegError> {
+        self.encode_file(cover_image, input_data, metadata, output, false)
+            .map(|_| ())
+    }
+
+    // Same as encode_with_metadata, also comparing the output image with the cover image
+    pub fn encode_with_report<R1: BufRead + Read + Seek, R2: Read, W: Write>(
+        &self,
+        cover_image: R1,
+        input_data: &mut R2,
+        metadata: &Metadata,
+        output: &mut W,
+    ) -> Result<QualityReport, StegError> {
+        self.encode_file(cover_image, input_data, metadata, output, true)
+            .map(|report| report.expect("report was requested"))
+    }
+
+    // Hides the input data in a copy of an image that is already decoded. The output image has
+    // the colour type the output format would be written with, it is up to the caller to write
+    // it in a lossless format
+    pub fn encode_image<R: Read>(
+        &self,
+        cover_image: &DynamicImage,
+        input_data: &mut R,
+    ) -> Result<DynamicImage, StegError> {
+        self.encode_image_with_metadata(cover_image, input_data, &Metadata::default())
+    }
+
+    // Same as encode_image, along with information about the file the data came from
+    pub fn encode_image_with_metadata<R: Read>(
+        &self,
+        cover_image: &DynamicImage,
+        input_data: &mut R,
+        metadata: &Metadata,
+    ) -> Result<DynamicImage, StegError> {
+        let (out_image, _) = self.embed_image(cover_image.clone(), input_data, metadata, false)?;
+        Ok(out_image)
+    }
+
+    // Writes an image returned by encode_image, encode_images or encode_shares in the output
+    // format, the same way encode writes its output
+    pub fn write_image<W: Write>(
+        &self,
+        image: &DynamicImage,
+        output: &mut W,
+    ) -> Result<(), StegError> {
+        format::write(image, self.output_format, output)
+    }
+
+    // Splits the input data across several cover images that are already decoded, returning one
+    // output image per cover. The prepared payload is shared out in proportion to the capacity of
+    // each cover, and every header names the payload and which fragment of it the image carries,
+    // so that the decoder can put them back together in any order. Each fragment has its own
+    // error correction
+    pub fn encode_images<R: Read>(
+        &self,
+        cover_images: &[DynamicImage],
+        input_data: &mut R,
+        metadata: &Metadata,
+    ) -> Result<Vec<DynamicImage>, StegError> {
+        format::check_output_format(self.output_format)?;
+
+        let count = u16::try_from(cover_images.len().max(1)).map_err(|_| {
+            StegError::InvalidMetadata(format!(
+                "a payload can not be split across more than {} images",
+                u16::MAX
+            ))
+        })?;
+        let fragment = Fragment {
+            message_id: ChaCha20Rng::from_entropy().gen(),
+            index: 0,
+            count,
+        };
+
+        // Every header carries the fragment count, so the payload has to be read in full first
+        let mut header = self.message_header(metadata);
+        let mut message = Vec::new();
+        self.seal(&mut header, input_data)?
+            .read_to_end(&mut message)?;
+
+        let mut template = self.header_template(metadata);
+        template.fragment = Some(fragment);
+        let header_size = template.to_nibbles()?.len();
+        let capacities = cover_images
+            .iter()
+            .map(|img| {
+                let carrier_len = self.carrier_len(img)?;
+                let available = carrier_len.saturating_sub(header_size)
+                    / self.byte_split_level.parts_per_byte();
+                Ok(match self.error_correction {
+                    Some(ec) => fec::max_data_len(ec, available),
+                    None => available,
+                })
+            })
+            .collect::<Result<Vec<usize>, StegError>>()?;
+
+        let total: usize = capacities.iter().sum();
+        if message.len() > total {
+            return Err(StegError::CoverTooSmall {
+                needed: message.len(),
+                available: total,
+            });
+        }
+
+        let mut out_images = Vec::with_capacity(cover_images.len());
+        let mut start = 0;
+        let mut cumulative = 0;
+        for (index, (img, capacity)) in cover_images.iter().zip(capacities).enumerate() {
+            cumulative += capacity;
+            let end = if index + 1 == cover_images.len() {
+                message.len()
+            } else {
+                (message.len() as u128 * cumulative as u128 / total as u128) as usize
+            };
+
+            let mut header = header.clone();
+            header.fragment = Some(Fragment {
+                index: index as u16,
+                ..fragment
+            });
+            let data = self.protect(&mut header, Box::new(&message[start..end]));
+            let (out_image, _) = self.embed_prepared(img.clone(), header, data, metadata, false)?;
+            out_images.push(out_image);
+            start = end;
+        }
+
+        Ok(out_images)
+    }
+
+    // Splits the input data into one share per cover image with Shamir's secret sharing, any
+    // threshold of the output images reconstruct it and fewer reveal nothing about it but its
+    // size. Every share is as large as the prepared payload, so every cover has to fit all of it,
+    // and the payload is read into memory to compute them. There is no metadata, it would be
+    // readable from every image
+    pub fn encode_shares<R: Read>(
+        &self,
+        cover_images: &[DynamicImage],
+        threshold: usize,
+        input_data: &mut R,
+    ) -> Result<Vec<DynamicImage>, StegError> {
+        format::check_output_format(self.output_format)?;
+
+        let count = cover_images.len();
+        if threshold == 0 || threshold > count || count > u8::MAX as usize {
+            return Err(StegError::InvalidMetadata(format!(
+                "can not split a payload into {} shares with a threshold of {}, there can be at \
+                 most {} shares",
+                count,
+                threshold,
+                u8::MAX
+            )));
+        }
+
+        let mut rng = ChaCha20Rng::from_entropy();
+        let share = Share {
+            secret_id: rng.gen(),
+            index: 0,
+            threshold: threshold as u8,
+            count: count as u8,
+        };
+
+        let metadata = Metadata::default();
+        let mut header = self.message_header(&metadata);
+        let mut message = Vec::new();
+        self.seal(&mut header, input_data)?
+            .read_to_end(&mut message)?;
+
+        let shares = shamir::split(&message, threshold, count, &mut rng);
+        cover_images
+            .iter()
+            .zip(shares)
+            .enumerate()
+            .map(|(index, (img, data))| {
+                let mut header = header.clone();
+                header.share = Some(Share {
+                    index: index as u8,
+                    ..share
+                });
+                let data = self.protect(&mut header, Box::new(Cursor::new(data)));
+                let (out_image, _) =
+                    self.embed_prepared(img.clone(), header, data, &metadata, false)?;
+                Ok(out_image)
+            })
+            .collect()
+    }
+
+    // The cover image is decoded straight from its reader and the data is embedded as it is read.
+    // The data is buffered when it is encrypted, compressed with Auto or the granularity has to
+    // be picked to fit it, the cover image when its ancillary chunks are copied and the output for
+    // formats other than PNG and WebP. See the Memory section of the README for the scatter order
+    fn encode_file<R1: BufRead + Read + Seek, R2: Read, W: Write>(
+        &self,
+        mut cover_image: R1,
+        input_data: &mut R2,
+        metadata: &Metadata,
+        output: &mut W,
+        with_report: bool,
+    ) -> Result<Option<QualityReport>, StegError> {
+        format::check_output_format(self.output_format)?;
+
+        let (img, cover_bytes) = if self.ancillary_chunks {
+            let mut cover_bytes = Vec::new();
+            cover_image.read_to_end(&mut cover_bytes)?;
+            let (img, cover_format) = format::load(&cover_bytes, self.cover_format)?;
+            (
+                img,
+                Some(cover_bytes).filter(|_| cover_format == ImageFormat::Png),
+            )
+        } else {
+            (format::read(cover_image, self.cover_format)?.0, None)
+        };
+
+        let (out_image, report) = self.embed_image(img, input_data, metadata, with_report)?;
+
+        match cover_bytes {
+            Some(cover_bytes) if self.output_format == ImageFormat::Png => {
+                let mut out = Vec::new();
+                format::write(&out_image, self.output_format, &mut out)?;
+                out =
+                    chunks::copy_ancillary(&cover_bytes, &out).map_err(StegError::MalformedPng)?;
+                output.write_all(&out)?;
             }
-            Err(err) => Err(err_to_io_error(err)),
+            _ => format::write(&out_image, self.output_format, output)?,
         }
+
+        Ok(report)
+    }
+
+    // Prepares the input data and embeds it in the cover image
+    fn embed_image<R: Read>(
+        &self,
+        img: DynamicImage,
+        input_data: &mut R,
+        metadata: &Metadata,
+        with_report: bool,
+    ) -> Result<(DynamicImage, Option<QualityReport>), StegError> {
+        format::check_output_format(self.output_format)?;
+
+        let mut header = self.message_header(metadata);
+        let data = self.seal(&mut header, input_data)?;
+        let data = self.protect(&mut header, data);
+        self.embed_prepared(img, header, data, metadata, with_report)
+    }
+
+    // Converts the cover image to the colour type that carries the data and embeds the prepared
+    // data in it, comparing the result with the cover image when asked to or when the PSNR is
+    // limited
+    fn embed_prepared(
+        &self,
+        img: DynamicImage,
+        header: DataHeader,
+        mut data: Box<dyn Read + '_>,
+        metadata: &Metadata,
+        with_report: bool,
+    ) -> Result<(DynamicImage, Option<QualityReport>), StegError> {
+        // 16 bit images keep their depth where the output format allows it, the data goes into
+        // the low bits of each sample
+        let color_type = self.output_colour_type(img.color())?;
+        let mut out_image = format::convert(img, color_type);
+        let cover = if with_report || self.min_psnr.is_some() {
+            Some(out_image.clone())
+        } else {
+            None
+        };
+
+        match &mut out_image {
+            DynamicImage::ImageLuma8(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            DynamicImage::ImageLumaA8(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            DynamicImage::ImageRgb8(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            DynamicImage::ImageRgba8(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            DynamicImage::ImageLuma16(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            DynamicImage::ImageLumaA16(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            DynamicImage::ImageRgb16(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            DynamicImage::ImageRgba16(buf) => {
+                self.embed(buf, color_type, header, &mut data, metadata)
+            }
+            _ => unreachable!("output images are always 8 or 16 bit"),
+        }?;
+
+        let report = cover
+            .map(|cover| QualityReport::compare(&cover, &out_image))
+            .transpose()?;
+        if let (Some(report), Some(min_psnr)) = (&report, self.min_psnr) {
+            if report.psnr < min_psnr {
+                return Err(StegError::QualityTooLow {
+                    psnr: report.psnr,
+                    min_psnr,
+                });
+            }
+        }
+
+        Ok((out_image, report))
+    }
+
+    // Colour type of the image that carries the data: the cover's own colour type or RGBA,
+    // restricted to what the output format can store
+    fn output_colour_type(&self, color_type: ColorType) -> Result<ColorType, StegError> {
+        let color_type = if self.native_colour_type {
+            color_type
+        } else if is_16_bit(color_type) {
+            ColorType::Rgba16
+        } else {
+            ColorType::Rgba8
+        };
+
+        format::storable_colour_type(self.output_format, color_type)
+    }
+
+    fn embed<S: byte_encodings::Sample>(
+        &self,
+        cover_image_samples: &mut [S],
+        color_type: ColorType,
+        mut header: DataHeader,
+        data: &mut Box<dyn Read + '_>,
+        metadata: &Metadata,
+    ) -> Result<(), StegError> {
+        let mut template = self.header_template(metadata);
+        template.compress_input = header.compress_input;
+        template.fragment = header.fragment;
+        template.share = header.share;
+        let header_size = template.to_nibbles()?.len();
+        let layout = Layout::new(
+            cover_image_samples,
+            color_type,
+            self.channel_mask,
+            self.transparent_pixels,
+        );
+
+        if self.auto_granularity {
+            // The granularity depends on the amount of data, which is only known once it is read
+            let mut buffered = Vec::new();
+            data.read_to_end(&mut buffered)?;
+            header.granularity = self.select_granularity(layout.len(), header_size, buffered.len());
+            *data = Box::new(Cursor::new(buffered));
+        }
+
+        self.merge_into(cover_image_samples, &layout, header, header_size, data)
+    }
+
+    // Maximum number of input bytes that are guaranteed to fit into a cover image of the given
+    // dimensions, taking the header and any encryption overhead into account. When compressing,
+    // this assumes the input does not compress at all. Only transparent pixels carry data with
+    // with_transparent_pixels, which takes the cover image itself: use cover_capacity instead
+    pub fn capacity(
+        &self,
+        width: u32,
+        height: u32,
+        color_type: ColorType,
+        metadata: &Metadata,
+    ) -> Result<u64, StegError> {
+        debug!(
+            "capacity of {}x{} {:?} cover image",
+            width, height, color_type
+        );
+        if self.transparent_pixels {
+            return Err(StegError::CoverRequired);
+        }
+
+        let channels = layout::carrier_channels(
+            self.output_colour_type(color_type)?,
+            self.channel_mask,
+            self.transparent_pixels,
+        )
+        .len();
+        self.samples_capacity(width as u64 * height as u64 * channels as u64, metadata)
+    }
+
+    // Same as capacity, reading the dimensions and colour type from the cover image. With
+    // transparent pixels, only the pixels that are transparent are counted
+    pub fn cover_capacity<R: BufRead + Read + Seek>(
+        &self,
+        cover_image: R,
+        metadata: &Metadata,
+    ) -> Result<u64, StegError> {
+        let (img, _) = format::read(cover_image, self.cover_format)?;
+        if !self.transparent_pixels {
+            let (width, height) = img.dimensions();
+            return self.capacity(width, height, img.color(), metadata);
+        }
+
+        let color_type = self.output_colour_type(img.color())?;
+        let layout = Layout::of_image(&format::convert(img, color_type), self.channel_mask, true);
+        self.samples_capacity(layout.len() as u64, metadata)
+    }
+
+    // Number of samples of the cover image that carry the header and data
+    fn carrier_len(&self, img: &DynamicImage) -> Result<usize, StegError> {
+        let color_type = self.output_colour_type(img.color())?;
+        if self.transparent_pixels {
+            let img = format::convert(img.clone(), color_type);
+            return Ok(Layout::of_image(&img, self.channel_mask, true).len());
+        }
+
+        let (width, height) = img.dimensions();
+        let channels =
+            layout::carrier_channels(color_type, self.channel_mask, self.transparent_pixels);
+        Ok(width as usize * height as usize * channels.len())
+    }
+
+    // Capacity of the given number of carrier samples
+    fn samples_capacity(
+        &self,
+        cover_image_size: u64,
+        metadata: &Metadata,
+    ) -> Result<u64, StegError> {
+        let header = self.header_template(metadata).to_nibbles()?;
+        let available = cover_image_size.saturating_sub(header.len() as u64)
+            / self.byte_split_level.parts_per_byte() as u64;
+
+        let available = match self.error_correction {
+            Some(ec) => fec::max_data_len(ec, available as usize) as u64,
+            None => available,
+        };
+
+        let available = match self.passphrase {
+            Some(_) => available.saturating_sub(
+                (crypto::TAG_LENGTH + crate::seal_metadata(metadata)?.len()) as u64,
+            ),
+            None => available,
+        };
+
+        Ok(compression::max_input(self.compress_input, available))
+    }
+
+    // The header before the data is prepared
+    fn message_header(&self, metadata: &Metadata) -> DataHeader {
+        let mut header = DataHeader::new(self.compress_input, self.byte_split_level);
+        header.embedding_mode = self.embedding_mode;
+        header.channel_mask = self.channel_mask;
+        header.transparent_pixels = self.transparent_pixels;
+        header.metadata = metadata.clone();
+        header
+    }
+
+    // A header the same size as the one this encoder writes
+    fn header_template(&self, metadata: &Metadata) -> DataHeader {
+        let mut header = DataHeader::new(
+            compression::largest_header_codec(self.compress_input),
+            self.byte_split_level,
+        );
+        header.encryption = self
+            .passphrase
+            .as_ref()
+            .map(|_| crypto::EncryptionParams::generate());
+        if header.encryption.is_none() {
+            header.metadata = metadata.clone();
+        }
+        header.digest = digest::DataHasher::new(header.version, self.digest_kind)
+            .finish()
+            .1;
+        header.error_correction = self.error_correction;
+        header.embedding_mode = self.embedding_mode;
+        header.channel_mask = self.channel_mask;
+        header.transparent_pixels = self.transparent_pixels;
+        header
     }
 
     // Make sure that we can fit our encoded bytes into the cover image
     fn check_utilisation(
         &self,
-        cover_image: &[u8],
-        encode_output: &EncodeOutput,
-    ) -> Result<(), std::io::Error> {
-        let cover_image_size = cover_image.len();
-        let input_data_encoded_size = encode_output.len();
+        cover_image_size: usize,
+        input_data_encoded_size: usize,
+    ) -> Result<(), StegError> {
         let cover_image_utilisation =
             ((input_data_encoded_size as f64) / (cover_image_size as f64)) * 100.0;
 
@@ -97,68 +654,174 @@ impl Encoder {
         if input_data_encoded_size <= cover_image_size {
             Ok(())
         } else {
-            Err(err_to_io_error(format!(
-                "cover image is too small for input, perhaps try a different encoding granularity or compress! ({})",
-                info_string,
-            )))
+            Err(StegError::CoverTooSmall {
+                needed: input_data_encoded_size,
+                available: cover_image_size,
+            })
         }
     }
 
-    fn compress<R: Read>(&self, reader: &mut R) -> Result<Vec<u8>, std::io::Error> {
-        let mut compressed_data: Vec<u8> = Vec::new();
-        let mut encoder = GzEncoder::new(reader, Compression::default());
-        let uncompressed_bytes = encoder.read_to_end(&mut compressed_data)?;
-        debug!(
-            "compression ratio: {:.4}%",
-            ((compressed_data.len() as f64) / (uncompressed_bytes as f64)) * 100.0
-        );
+    // Compresses and encrypts the input data as configured, returning a reader of the payload. It
+    // streams unless the data is encrypted or compressed with Auto, which read all of it first
+    fn seal<'a, R: Read>(
+        &self,
+        header: &mut DataHeader,
+        input_data: &'a mut R,
+    ) -> Result<Box<dyn Read + 'a>, StegError> {
+        // Auto has to see all of the data to pick a codec
+        let mut data = if self.compress_input == CompressInput::Auto {
+            let mut input = Vec::new();
+            input_data.read_to_end(&mut input)?;
+            let (compress_input, compressed) = compression::compress_smallest(&input)?;
+            debug!("selected compression: {:?}", compress_input);
+            header.compress_input = compress_input;
+            Box::new(Cursor::new(compressed))
+        } else {
+            compression::compress(self.compress_input, input_data)?
+        };
+
+        // The cipher seals the whole payload at once, along with the metadata which then stays
+        // out of the header
+        if let Some(passphrase) = &self.passphrase {
+            let mut plaintext = crate::seal_metadata(&std::mem::take(&mut header.metadata))?;
+            data.read_to_end(&mut plaintext)?;
+            let params = crypto::EncryptionParams::generate();
+            header.encryption = Some(params);
+            let ciphertext =
+                crypto::encrypt(passphrase, &params, &header.associated_data()?, &plaintext)?;
+            data = Box::new(Cursor::new(ciphertext));
+        }
 
-        Ok(compressed_data)
+        Ok(data)
     }
 
-    fn encode_data<R: Read>(&self, reader: &mut R) -> Result<EncodeOutput, std::io::Error> {
-        let mut out: Vec<u8> = Vec::new();
-        let mut header = DataHeader::new(self.compress_input, self.byte_split_level);
-        let mut hasher = DefaultHasher::new();
+    // Adds error correction to the payload as configured, returning a reader of the bytes to embed
+    fn protect<'a>(&self, header: &mut DataHeader, data: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+        match self.error_correction {
+            Some(ec) => {
+                header.error_correction = Some(ec);
+                Box::new(fec::EncodeReader::new(ec, data))
+            }
+            None => data,
+        }
+    }
+
+    // Least invasive granularity, up to the configured one, that fits the data into the cover image
+    fn select_granularity(
+        &self,
+        cover_image_size: usize,
+        header_size: usize,
+        data_size: usize,
+    ) -> ByteSplitGranularity {
+        let granularity = [
+            ByteSplitGranularity::OneBit,
+            ByteSplitGranularity::TwoBits,
+            ByteSplitGranularity::FourBits,
+        ]
+        .iter()
+        .copied()
+        .take_while(|g| g.parts_per_byte() >= self.byte_split_level.parts_per_byte())
+        .find(|g| header_size + data_size * g.parts_per_byte() <= cover_image_size)
+        .unwrap_or(self.byte_split_level);
+
+        debug!("selected granularity: {:?}", granularity);
+        granularity
+    }
+
+    // Embeds the data, then the header, which depends on the data but goes into the positions
+    // that come first
+    fn merge_into<S: byte_encodings::Sample>(
+        &self,
+        dest: &mut [S],
+        layout: &Layout,
+        mut header: DataHeader,
+        header_size: usize,
+        data: &mut dyn Read,
+    ) -> Result<(), StegError> {
+        let seed = match &self.scatter_key {
+            Some(key) => Some(scatter::derive_seed(key)?),
+            None => None,
+        };
+
+        let mut positions = scatter::positions(layout.len(), seed).map(|p| layout.sample(p));
+        let header_positions: Vec<usize> = positions.by_ref().take(header_size).collect();
+
+        let mut rng = match self.embedding_mode {
+            EmbeddingMode::Replacement => None,
+            EmbeddingMode::Matching => Some(ChaCha20Rng::from_entropy()),
+        };
+
+        let mut hasher = digest::DataHasher::new(header.version, self.digest_kind);
+        let parts_per_byte = header.granularity.parts_per_byte();
         let mut bytes_count = 0;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut parts = Vec::with_capacity(CHUNK_SIZE * parts_per_byte);
+
+        loop {
+            let n = match data.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            bytes_count += n;
 
-        // 1. Explode data into multiple bytes, depending on byte_split_level
-        for b in reader.bytes() {
-            let bb = b?;
-            hasher.write_u8(bb);
-            let split = byte_encodings::split_byte(header.granularity, bb);
-            out.write_all(&split)?;
-            bytes_count += 1;
+            // Once the data does not fit, the rest of it is only read to tell how much is needed
+            if header_size + bytes_count * parts_per_byte > layout.len() {
+                bytes_count += std::io::copy(data, &mut std::io::sink())? as usize;
+                break;
+            }
+
+            hasher.update(&chunk[..n]);
+            parts.clear();
+            for b in &chunk[..n] {
+                parts.extend(byte_encodings::split_bits(header.granularity, *b));
+            }
+            self.zip(dest, &mut positions, &parts, header.granularity, &mut rng);
         }
 
+        self.check_utilisation(layout.len(), header_size + bytes_count * parts_per_byte)?;
+
         header.bytes_count = bytes_count as u64;
-        header.data_hash = hasher.finish();
-        header.compress_input = self.compress_input;
+        let (data_hash, digest) = hasher.finish();
+        header.data_hash = data_hash;
+        header.digest = digest;
 
         debug!("encode header: {:?}", header);
 
-        // Populate header in output
-        let raw_header: [u8; HEADER_LENGTH] = header.into();
-
-        Ok(EncodeOutput {
-            header: raw_header.to_vec(),
-            data: out,
-        })
-    }
-
-    fn merge_into(&self, dest: &mut [u8], src: EncodeOutput) {
-        byte_encodings::BytesZipper::merge_into(
-            &mut dest[0..HEADER_LENGTH],
-            &src.header,
+        let nibbles = header.to_nibbles()?;
+        // Anything else would overwrite the data or leave part of the header unwritten
+        if nibbles.len() != header_size {
+            return Err(StegError::InvalidHeader(format!(
+                "header takes {} samples but {} were set aside for it",
+                nibbles.len(),
+                header_size
+            )));
+        }
+        self.zip(
+            dest,
+            &mut header_positions.into_iter(),
+            &nibbles,
             ByteSplitGranularity::FourBits,
+            &mut rng,
         );
 
-        let src_size = src.data.len();
+        Ok(())
+    }
 
-        byte_encodings::BytesZipper::merge_into(
-            &mut dest[HEADER_LENGTH..(HEADER_LENGTH + src_size)],
-            &src.data,
-            self.byte_split_level,
-        );
+    fn zip<S: byte_encodings::Sample, I: Iterator<Item = usize>>(
+        &self,
+        dest: &mut [S],
+        positions: &mut I,
+        src: &[u8],
+        granularity: ByteSplitGranularity,
+        rng: &mut Option<ChaCha20Rng>,
+    ) {
+        match rng {
+            Some(rng) => {
+                byte_encodings::BytesZipper::match_into(dest, positions, src, granularity, rng)
+            }
+            None => byte_encodings::BytesZipper::merge_into(dest, positions, src, granularity),
+        }
     }
 }
@@ -0,0 +1,202 @@
+use crate::DigestKind;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum StegError {
+    // The image does not start with a header, it most likely does not carry any data
+    InvalidMagic(u16),
+    UnsupportedVersion(u8),
+    // The header is truncated or holds values that can not be read
+    InvalidHeader(String),
+    // The metadata can not be stored in the header
+    InvalidMetadata(String),
+    // The data extracted from the image does not match the hash printed in the header
+    HashMismatch { expected: u64, actual: u64 },
+    // The data extracted from the image does not match the digest printed in the header
+    DigestMismatch(DigestKind),
+    // A block of the data has more corrupted bytes than its parity bytes can repair
+    UncorrectableData { block: usize },
+    // Fragments of a payload that was split across several images were not given
+    MissingFragments { missing: Vec<usize>, count: usize },
+    // The images carry fragments of different payloads
+    MixedFragments,
+    // Fewer images carrying shares of the payload were given than it was split to need
+    MissingShares { given: usize, threshold: usize },
+    // The images carry shares of different payloads, or some of them no share at all
+    MixedShares,
+    // The image holds fewer bytes than the header says were embedded
+    TruncatedData { needed: usize, available: usize },
+    // The encoded header and data take more bytes than the cover image has
+    CoverTooSmall { needed: usize, available: usize },
+    // The capacity depends on the pixels of the cover image, not only on its dimensions
+    CoverRequired,
+    // The output image differs more from the cover image than the encoder allows
+    QualityTooLow { psnr: f64, min_psnr: f64 },
+    PassphraseRequired,
+    WrongPassphrase,
+    // Key derivation or encryption failed
+    Crypto(String),
+    ImageFormat(image::ImageError),
+    // The output format would not keep the embedded data intact
+    LossyFormat(image::ImageFormat),
+    UnsupportedFormat(image::ImageFormat),
+    // The chunks of the PNG image can not be read
+    MalformedPng(String),
+    Compression(std::io::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for StegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StegError::InvalidMagic(magic) => {
+                write!(f, "validation failure: invalid magic: {:#x}", magic)
+            }
+            StegError::UnsupportedVersion(version) => {
+                write!(f, "validation failure: unsupported version: {:#x}", version)
+            }
+            StegError::InvalidHeader(reason) => write!(f, "validation failure: {}", reason),
+            StegError::InvalidMetadata(reason) => write!(f, "invalid metadata: {}", reason),
+            StegError::HashMismatch { expected, actual } => write!(
+                f,
+                "validation failure: data hash {} does not match hash printed in header {}",
+                actual, expected
+            ),
+            StegError::DigestMismatch(kind) => write!(
+                f,
+                "validation failure: {:?} digest does not match digest printed in header",
+                kind
+            ),
+            StegError::UncorrectableData { block } => write!(
+                f,
+                "validation failure: block {} of the data has too many errors to correct",
+                block
+            ),
+            StegError::MissingFragments { missing, count } => write!(
+                f,
+                "validation failure: fragments {:?} of {} fragments are missing",
+                missing, count
+            ),
+            StegError::MixedFragments => write!(
+                f,
+                "validation failure: images carry fragments of different payloads"
+            ),
+            StegError::MissingShares { given, threshold } => write!(
+                f,
+                "validation failure: {} of {} shares were given, {} more are needed",
+                given,
+                threshold,
+                threshold - given
+            ),
+            StegError::MixedShares => write!(
+                f,
+                "validation failure: images carry shares of different payloads"
+            ),
+            StegError::TruncatedData { needed, available } => write!(
+                f,
+                "validation failure: image data is too small/does not match bytes count in header \
+                 (needed: {}, available: {})",
+                needed, available
+            ),
+            StegError::CoverTooSmall { needed, available } => write!(
+                f,
+                "cover image is too small for input, perhaps try a different encoding granularity \
+                 or compress! (needed: {}, available: {})",
+                needed, available
+            ),
+            StegError::CoverRequired => write!(
+                f,
+                "the capacity of transparent pixels depends on the cover image, it can not be told \
+                 from its dimensions"
+            ),
+            StegError::QualityTooLow { psnr, min_psnr } => write!(
+                f,
+                "output image PSNR of {:.2} dB is below the minimum of {:.2} dB",
+                psnr, min_psnr
+            ),
+            StegError::PassphraseRequired => write!(
+                f,
+                "validation failure: payload is encrypted but no passphrase was provided"
+            ),
+            StegError::WrongPassphrase => {
+                write!(f, "decryption failure: passphrase is incorrect")
+            }
+            StegError::Crypto(reason) => write!(f, "{}", reason),
+            StegError::ImageFormat(err) => write!(f, "{}", err),
+            StegError::LossyFormat(format) => write!(
+                f,
+                "{:?} is a lossy format, it would destroy the embedded data",
+                format
+            ),
+            StegError::UnsupportedFormat(format) => {
+                write!(f, "{:?} is not supported as an output format", format)
+            }
+            StegError::MalformedPng(reason) => write!(f, "malformed PNG image: {}", reason),
+            StegError::Compression(err) => write!(f, "compression failure: {}", err),
+            StegError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for StegError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StegError::ImageFormat(err) => Some(err),
+            StegError::Compression(err) => Some(err),
+            StegError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StegError {
+    fn from(err: std::io::Error) -> Self {
+        StegError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for StegError {
+    fn from(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(err) => StegError::Io(err),
+            err => StegError::ImageFormat(err),
+        }
+    }
+}
+
+// Keeps the error kinds the library reported before it had its own error type, the StegError
+// can be recovered from the io::Error with get_ref and downcast_ref
+impl From<StegError> for std::io::Error {
+    fn from(err: StegError) -> Self {
+        match err {
+            StegError::Io(err) => err,
+            StegError::WrongPassphrase => {
+                std::io::Error::new(std::io::ErrorKind::PermissionDenied, err)
+            }
+            StegError::Compression(ref inner) => {
+                let kind = inner.kind();
+                std::io::Error::new(kind, err)
+            }
+            _ => std::io::Error::other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_io_error() {
+        let err = std::io::Error::from(StegError::WrongPassphrase);
+        assert_eq!(std::io::ErrorKind::PermissionDenied, err.kind());
+        assert!(matches!(
+            err.get_ref().unwrap().downcast_ref::<StegError>(),
+            Some(StegError::WrongPassphrase)
+        ));
+
+        let err = std::io::Error::from(StegError::Io(std::io::ErrorKind::NotFound.into()));
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        assert!(err.get_ref().is_none());
+    }
+}
@@ -0,0 +1,333 @@
+use crate::error::StegError;
+use crate::gf256;
+use crate::ErrorCorrection;
+use std::io::Read;
+
+// Reed-Solomon code over GF(256). The data is cut into blocks of data_length bytes, each followed
+// by its parity bytes. The last block is shortened, it only holds the remaining data
+
+// Largest amount of data whose encoding fits into the available bytes
+pub fn max_data_len(ec: ErrorCorrection, available: usize) -> usize {
+    let full_blocks = available / ec.block_length();
+    let rest = available % ec.block_length();
+    full_blocks * ec.data_length() + rest.saturating_sub(ec.parity_length())
+}
+
+// Reads the data with the parity bytes of each block added after it
+pub struct EncodeReader<R> {
+    ec: ErrorCorrection,
+    generator: Vec<u8>,
+    inner: R,
+    block: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> EncodeReader<R> {
+    pub fn new(ec: ErrorCorrection, inner: R) -> Self {
+        Self {
+            ec,
+            generator: generator(ec.parity_length()),
+            inner,
+            block: Vec::with_capacity(ec.block_length()),
+            position: 0,
+        }
+    }
+
+    // Reads the next block of data and adds its parity bytes, leaving the block empty at the end
+    // of the data
+    fn fill_block(&mut self) -> std::io::Result<()> {
+        self.block.clear();
+        self.position = 0;
+        (&mut self.inner)
+            .take(self.ec.data_length() as u64)
+            .read_to_end(&mut self.block)?;
+
+        if !self.block.is_empty() {
+            let parity = parity(&self.generator, &self.block);
+            self.block.extend(parity);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for EncodeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.block.len() {
+            self.fill_block()?;
+        }
+
+        let n = buf.len().min(self.block.len() - self.position);
+        buf[..n].copy_from_slice(&self.block[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+// Corrects the encoded data in place, returning the number of bytes that were corrupted
+pub fn correct(ec: ErrorCorrection, encoded: &mut [u8]) -> Result<usize, StegError> {
+    let mut corrected = 0;
+
+    for (i, block) in encoded.chunks_mut(ec.block_length()).enumerate() {
+        if block.len() <= ec.parity_length() {
+            return Err(StegError::InvalidHeader(
+                "data length does not match the error correction blocks".to_string(),
+            ));
+        }
+
+        corrected += correct_block(block, ec.parity_length())
+            .ok_or(StegError::UncorrectableData { block: i })?;
+    }
+
+    Ok(corrected)
+}
+
+// The encoded data without its parity bytes
+pub fn strip_parity(ec: ErrorCorrection, encoded: &[u8]) -> Vec<u8> {
+    encoded
+        .chunks(ec.block_length())
+        .flat_map(|block| &block[..block.len().saturating_sub(ec.parity_length())])
+        .copied()
+        .collect()
+}
+
+// (x - a^0)(x - a^1)...(x - a^(parity_length - 1)), highest degree coefficient first
+fn generator(parity_length: usize) -> Vec<u8> {
+    let mut generator = vec![1];
+
+    for i in 0..parity_length {
+        let root = gf256::exp(i);
+        let mut next = vec![0; generator.len() + 1];
+        for (j, c) in generator.iter().enumerate() {
+            next[j] ^= c;
+            next[j + 1] ^= gf256::mul(*c, root);
+        }
+        generator = next;
+    }
+
+    generator
+}
+
+// Remainder of the block, shifted up by the number of parity bytes, divided by the generator
+fn parity(generator: &[u8], block: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0; generator.len() - 1];
+
+    for b in block {
+        let feedback = b ^ remainder[0];
+        remainder.rotate_left(1);
+        *remainder.last_mut().unwrap() = 0;
+
+        if feedback != 0 {
+            remainder
+                .iter_mut()
+                .zip(generator[1..].iter())
+                .for_each(|(r, g)| *r ^= gf256::mul(feedback, *g));
+        }
+    }
+
+    remainder
+}
+
+// Corrects a block in place, returning the number of corrupted bytes or None if there are more
+// than the parity bytes can correct. Byte i of the block is the coefficient of x^(len - 1 - i)
+fn correct_block(block: &mut [u8], parity_length: usize) -> Option<usize> {
+    let syndromes = syndromes_of(block, parity_length);
+    if syndromes.iter().all(|s| *s == 0) {
+        return Some(0);
+    }
+
+    let locator = error_locator(&syndromes);
+    let errors = locator.len() - 1;
+    if errors * 2 > parity_length {
+        return None;
+    }
+
+    // Chien search: there is an error at x^e if a^-e is a root of the locator
+    let powers: Vec<usize> = (0..block.len())
+        .filter(|e| eval_low_first(&locator, gf256::exp(255 - e)) == 0)
+        .collect();
+    if powers.len() != errors {
+        return None;
+    }
+
+    // Forney: the error at x^e is X * omega(X^-1) / locator'(X^-1) with X = a^e
+    let mut evaluator = vec![0; parity_length];
+    for (i, s) in syndromes.iter().enumerate() {
+        for (j, l) in locator.iter().enumerate().take(parity_length - i) {
+            evaluator[i + j] ^= gf256::mul(*s, *l);
+        }
+    }
+
+    // Formal derivative, the even powers cancel out in characteristic 2
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, l)| if i % 2 == 1 { *l } else { 0 })
+        .collect();
+
+    for e in powers {
+        let x_inv = gf256::exp(255 - e);
+        let denominator = eval_low_first(&derivative, x_inv);
+        if denominator == 0 {
+            return None;
+        }
+
+        let magnitude = gf256::div(
+            gf256::mul(gf256::exp(e), eval_low_first(&evaluator, x_inv)),
+            denominator,
+        );
+        let len = block.len();
+        block[len - 1 - e] ^= magnitude;
+    }
+
+    if syndromes_of(block, parity_length).iter().any(|s| *s != 0) {
+        return None;
+    }
+
+    Some(errors)
+}
+
+// The block evaluated at the roots of the generator, all zero for a valid block
+fn syndromes_of(block: &[u8], parity_length: usize) -> Vec<u8> {
+    (0..parity_length)
+        .map(|i| gf256::eval(block, gf256::exp(i)))
+        .collect()
+}
+
+// Berlekamp-Massey, returns the error locator with the lowest degree coefficient first
+fn error_locator(syndromes: &[u8]) -> Vec<u8> {
+    let mut locator = vec![1];
+    let mut previous = vec![1];
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1;
+
+    for n in 0..syndromes.len() {
+        let discrepancy = (1..=errors).fold(syndromes[n], |acc, i| {
+            acc ^ gf256::mul(locator.get(i).copied().unwrap_or(0), syndromes[n - i])
+        });
+
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let scale = gf256::div(discrepancy, previous_discrepancy);
+        let mut next = locator.clone();
+        next.resize(next.len().max(previous.len() + shift), 0);
+        for (i, p) in previous.iter().enumerate() {
+            next[i + shift] ^= gf256::mul(scale, *p);
+        }
+
+        if 2 * errors <= n {
+            errors = n + 1 - errors;
+            previous = locator;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+        locator = next;
+    }
+
+    locator.resize(errors + 1, 0);
+    locator
+}
+
+fn eval_low_first(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, c| gf256::mul(acc, x) ^ c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Number of bytes the data takes once its parity bytes are added
+    fn encoded_len(ec: ErrorCorrection, data_len: usize) -> usize {
+        let blocks = data_len.div_ceil(ec.data_length());
+        data_len + blocks * ec.parity_length()
+    }
+
+    fn encode(ec: ErrorCorrection, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        EncodeReader::new(ec, data)
+            .read_to_end(&mut out)
+            .expect("no error");
+        out
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let ec = ErrorCorrection::new(0.2);
+        assert_eq!(51, ec.parity_length());
+
+        assert_eq!(0, encoded_len(ec, 0));
+        assert_eq!(255, encoded_len(ec, 204));
+        assert_eq!(255 + 52, encoded_len(ec, 205));
+        assert_eq!(205, max_data_len(ec, 255 + 52));
+        assert_eq!(204, max_data_len(ec, 255 + 51));
+
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        assert_eq!(encoded_len(ec, data.len()), encode(ec, &data).len());
+    }
+
+    #[test]
+    fn test_encode_reader() {
+        let ec = ErrorCorrection::new(0.1);
+        let data: Vec<u8> = (0..1000).map(|i| (i * 11 + 3) as u8).collect();
+
+        // Reading a byte at a time gives the same blocks
+        let mut encoded = Vec::new();
+        let mut reader = EncodeReader::new(ec, data.as_slice());
+        let mut byte = [0];
+        while reader.read(&mut byte).expect("no error") == 1 {
+            encoded.push(byte[0]);
+        }
+        assert_eq!(encode(ec, &data), encoded);
+
+        for (block, chunk) in encoded
+            .chunks(ec.block_length())
+            .zip(data.chunks(ec.data_length()))
+        {
+            assert_eq!(chunk, &block[..chunk.len()]);
+        }
+
+        assert!(encode(ec, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_correct() {
+        let ec = ErrorCorrection::new(0.1);
+        let data: Vec<u8> = (0..600).map(|i| (i * 13 + 5) as u8).collect();
+        let mut encoded = encode(ec, &data);
+
+        assert_eq!(0, correct(ec, &mut encoded).expect("no error"));
+
+        // Up to half the parity bytes of every block, the last one is shortened
+        let max_errors = ec.parity_length() / 2;
+        for block in encoded.chunks_mut(ec.block_length()) {
+            for i in 0..max_errors {
+                block[i * 7 % block.len()] ^= 0x5a;
+            }
+        }
+
+        assert_eq!(3 * max_errors, correct(ec, &mut encoded).expect("no error"));
+        assert_eq!(data, strip_parity(ec, &encoded));
+    }
+
+    #[test]
+    fn test_too_many_errors() {
+        let ec = ErrorCorrection::new(0.1);
+        let data = vec![0x42; 100];
+        let mut encoded = encode(ec, &data);
+
+        for b in encoded.iter_mut().take(ec.parity_length() / 2 + 1) {
+            *b ^= 0xff;
+        }
+
+        assert!(matches!(
+            correct(ec, &mut encoded),
+            Err(StegError::UncorrectableData { block: 0 })
+        ));
+    }
+}
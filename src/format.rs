@@ -0,0 +1,204 @@
+use crate::error::StegError;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::io::Reader;
+use image::{ColorType, DynamicImage, ImageError, ImageFormat, ImageOutputFormat};
+use std::io::{BufRead, Cursor, Seek, Write};
+
+// Formats that would not keep the embedded bits intact
+const LOSSY_FORMATS: [ImageFormat; 3] = [ImageFormat::Jpeg, ImageFormat::Gif, ImageFormat::Avif];
+
+// Lossless formats the encoder can write, along with the colour types they can store
+fn supported_colour_types(format: ImageFormat) -> Result<&'static [ColorType], StegError> {
+    use ColorType::*;
+
+    match format {
+        ImageFormat::Png => Ok(&[L8, La8, Rgb8, Rgba8, L16, La16, Rgb16, Rgba16]),
+        ImageFormat::Tiff => Ok(&[L8, Rgb8, Rgba8, L16, Rgb16, Rgba16]),
+        // The PNM decoder can not read alpha channels back and the encoder can not write 16 bit
+        ImageFormat::Pnm => Ok(&[L8, Rgb8]),
+        ImageFormat::Tga => Ok(&[L8, La8, Rgb8, Rgba8]),
+        // Grayscale images are read back with colour channels
+        ImageFormat::Bmp | ImageFormat::Qoi => Ok(&[Rgb8, Rgba8]),
+        // Lossless WebP images are always read back with an alpha channel
+        ImageFormat::WebP => Ok(&[Rgba8]),
+        format if LOSSY_FORMATS.contains(&format) => Err(StegError::LossyFormat(format)),
+        format => Err(StegError::UnsupportedFormat(format)),
+    }
+}
+
+pub(crate) fn check_output_format(format: ImageFormat) -> Result<(), StegError> {
+    supported_colour_types(format).map(|_| ())
+}
+
+// Closest colour type to the given one that the format can store: the colour type itself,
+// otherwise the same depth with colour channels added, otherwise the 8 bit equivalents, and as a
+// last resort without the alpha channel
+pub(crate) fn storable_colour_type(
+    format: ImageFormat,
+    color_type: ColorType,
+) -> Result<ColorType, StegError> {
+    let supported = supported_colour_types(format)?;
+    let with_colour = |color_type| match color_type {
+        ColorType::L8 => ColorType::Rgb8,
+        ColorType::La8 => ColorType::Rgba8,
+        ColorType::L16 => ColorType::Rgb16,
+        ColorType::La16 => ColorType::Rgba16,
+        color_type => color_type,
+    };
+    let to_8_bit = |color_type| match color_type {
+        ColorType::L16 => ColorType::L8,
+        ColorType::La16 => ColorType::La8,
+        ColorType::Rgb16 | ColorType::Rgb32F => ColorType::Rgb8,
+        ColorType::Rgba16 | ColorType::Rgba32F => ColorType::Rgba8,
+        color_type => color_type,
+    };
+    let without_alpha = |color_type| match color_type {
+        ColorType::La8 => ColorType::L8,
+        ColorType::Rgba8 => ColorType::Rgb8,
+        ColorType::La16 => ColorType::L16,
+        ColorType::Rgba16 => ColorType::Rgb16,
+        color_type => color_type,
+    };
+
+    Ok([
+        color_type,
+        with_colour(color_type),
+        to_8_bit(color_type),
+        to_8_bit(with_colour(color_type)),
+        without_alpha(to_8_bit(color_type)),
+        without_alpha(to_8_bit(with_colour(color_type))),
+    ]
+    .iter()
+    .copied()
+    .find(|candidate| supported.contains(candidate))
+    .unwrap_or(ColorType::Rgba8))
+}
+
+pub(crate) fn convert(img: DynamicImage, color_type: ColorType) -> DynamicImage {
+    if img.color() == color_type {
+        return img;
+    }
+
+    match color_type {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        _ => DynamicImage::ImageRgba8(img.to_rgba8()),
+    }
+}
+
+// Decodes an image in any format the image crate can read, telling the format from its content.
+// TGA files do not start with a magic number, so input that is not recognised is only read in the
+// fallback format the caller gives, and refused as an unsupported format otherwise
+pub(crate) fn load(
+    bytes: &[u8],
+    fallback: Option<ImageFormat>,
+) -> Result<(DynamicImage, ImageFormat), StegError> {
+    read(Cursor::new(bytes), fallback)
+}
+
+// Same as load, decoding the image straight from the reader
+pub(crate) fn read<R: BufRead + Seek>(
+    reader: R,
+    fallback: Option<ImageFormat>,
+) -> Result<(DynamicImage, ImageFormat), StegError> {
+    let mut reader = Reader::new(reader).with_guessed_format()?;
+    let format = reader.format().or(fallback).ok_or_else(|| {
+        ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormatHint::Unknown,
+            UnsupportedErrorKind::Format(ImageFormatHint::Unknown),
+        ))
+    })?;
+    reader.set_format(format);
+    Ok((reader.decode()?, format))
+}
+
+pub(crate) fn write<W: Write>(
+    img: &DynamicImage,
+    format: ImageFormat,
+    output: &mut W,
+) -> Result<(), StegError> {
+    check_output_format(format)?;
+
+    match format {
+        ImageFormat::Png => img.write_with_encoder(PngEncoder::new(output))?,
+        // Written explicitly so that the output does not depend on the default WebP quality
+        ImageFormat::WebP => img.write_with_encoder(WebPEncoder::new_lossless(output))?,
+        // The other encoders are only reachable through write_to, which needs to seek
+        format => {
+            let mut out = Cursor::new(Vec::new());
+            img.write_to(&mut out, ImageOutputFormat::from(format))?;
+            output.write_all(out.get_ref())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storable_colour_type() {
+        let storable = |format, color_type| storable_colour_type(format, color_type).unwrap();
+
+        assert_eq!(ColorType::La16, storable(ImageFormat::Png, ColorType::La16));
+        assert_eq!(
+            ColorType::Rgba16,
+            storable(ImageFormat::Tiff, ColorType::La16)
+        );
+        assert_eq!(ColorType::L8, storable(ImageFormat::Pnm, ColorType::La16));
+        assert_eq!(
+            ColorType::Rgb8,
+            storable(ImageFormat::Pnm, ColorType::Rgba16)
+        );
+        assert_eq!(
+            ColorType::Rgb8,
+            storable(ImageFormat::Bmp, ColorType::Rgb16)
+        );
+        assert_eq!(ColorType::Rgb8, storable(ImageFormat::Qoi, ColorType::L8));
+        assert_eq!(ColorType::Rgba8, storable(ImageFormat::WebP, ColorType::L8));
+        assert_eq!(
+            ColorType::Rgba8,
+            storable(ImageFormat::Qoi, ColorType::La16)
+        );
+    }
+
+    #[test]
+    fn test_load_unrecognised_format() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(2, 2));
+        let mut tga = Cursor::new(Vec::new());
+        img.write_to(&mut tga, ImageFormat::Tga).unwrap();
+        let tga = tga.into_inner();
+
+        for bytes in [&tga[..], b"not an image"] {
+            assert!(matches!(
+                load(bytes, None),
+                Err(StegError::ImageFormat(ImageError::Unsupported(_)))
+            ));
+        }
+
+        let (loaded, format) = load(&tga, Some(ImageFormat::Tga)).expect("no error");
+        assert_eq!(ImageFormat::Tga, format);
+        assert_eq!(img, loaded);
+    }
+
+    #[test]
+    fn test_lossy_output_format() {
+        assert!(matches!(
+            check_output_format(ImageFormat::Jpeg),
+            Err(StegError::LossyFormat(ImageFormat::Jpeg))
+        ));
+        assert!(matches!(
+            check_output_format(ImageFormat::Hdr),
+            Err(StegError::UnsupportedFormat(ImageFormat::Hdr))
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+// Arithmetic in GF(2^8), using the field polynomial x^8 + x^4 + x^3 + x^2 + 1
+const POLYNOMIAL: u16 = 0x11d;
+
+// Powers of the generator 2, doubled up so that the sum of two logarithms can be looked up
+// without reducing it modulo 255
+const EXP: [u8; 512] = exp_table();
+const LOG: [u8; 256] = log_table();
+
+const fn exp_table() -> [u8; 512] {
+    let mut table = [0; 512];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < table.len() {
+        table[i] = x as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= POLYNOMIAL;
+        }
+        i += 1;
+    }
+    table
+}
+
+const fn log_table() -> [u8; 256] {
+    let exp = exp_table();
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 255 {
+        table[exp[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+// The generator raised to the given power
+pub fn exp(power: usize) -> u8 {
+    EXP[power % 255]
+}
+
+pub fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+}
+
+pub fn div(a: u8, b: u8) -> u8 {
+    assert!(b != 0, "division by zero in GF(256)");
+    if a == 0 {
+        return 0;
+    }
+    EXP[LOG[a as usize] as usize + 255 - LOG[b as usize] as usize]
+}
+
+// Evaluates the polynomial, given highest degree coefficient first, at x
+pub fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().fold(0, |acc, c| mul(acc, x) ^ c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_arithmetic() {
+        assert_eq!(1, exp(0));
+        assert_eq!(0x1d, exp(8));
+        assert_eq!(exp(3), mul(exp(1), exp(2)));
+
+        for a in 1..=255u8 {
+            assert_eq!(1, mul(a, div(1, a)));
+            assert_eq!(a, div(mul(a, 0x53), 0x53));
+        }
+
+        // x^2 + 1 at x = 2
+        assert_eq!(5, eval(&[1, 0, 1], 2));
+    }
+}
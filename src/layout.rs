@@ -0,0 +1,224 @@
+use crate::ChannelMask;
+use image::{ColorType, DynamicImage};
+
+// A run of fully transparent pixels, offset is the number of transparent pixels before it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Run {
+    pixel: usize,
+    offset: usize,
+}
+
+// The samples of an image that carry the header and data: the selected channels of every pixel,
+// or only of its fully transparent pixels. Positions from the scatter order index these carrier
+// samples and are mapped onto the samples of the image. Transparent pixels are remembered as runs,
+// which are few for the transparent areas of real images
+pub struct Layout {
+    channel_count: usize,
+    channels: Vec<usize>,
+    runs: Option<Vec<Run>>,
+    pixels: usize,
+}
+
+impl Layout {
+    pub fn new<S: Copy + Default + PartialEq>(
+        samples: &[S],
+        color_type: ColorType,
+        mask: ChannelMask,
+        transparent_pixels: bool,
+    ) -> Self {
+        let channel_count = color_type.channel_count() as usize;
+        let channels = carrier_channels(color_type, mask, transparent_pixels);
+
+        let (runs, pixels) = if transparent_pixels {
+            let runs = transparent_runs(samples, color_type);
+            let pixels = runs.iter().map(|(_, len)| len).sum();
+            let mut offset = 0;
+            let runs = runs
+                .into_iter()
+                .map(|(pixel, len)| {
+                    offset += len;
+                    Run {
+                        pixel,
+                        offset: offset - len,
+                    }
+                })
+                .collect();
+            (Some(runs), pixels)
+        } else {
+            (None, samples.len() / channel_count)
+        };
+
+        Self {
+            channel_count,
+            channels,
+            runs,
+            pixels,
+        }
+    }
+
+    // Layout of an image read back by the decoder, in the same samples decoder::image_bytes reads
+    pub fn of_image(img: &DynamicImage, mask: ChannelMask, transparent_pixels: bool) -> Self {
+        let color_type = img.color();
+        match img {
+            DynamicImage::ImageLuma8(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            DynamicImage::ImageLumaA8(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            DynamicImage::ImageRgb8(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            DynamicImage::ImageRgba8(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            DynamicImage::ImageLuma16(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            DynamicImage::ImageLumaA16(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            DynamicImage::ImageRgb16(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            DynamicImage::ImageRgba16(buf) => Self::new(buf, color_type, mask, transparent_pixels),
+            _ => Self::new(&img.to_rgba8(), ColorType::Rgba8, mask, transparent_pixels),
+        }
+    }
+
+    // Number of carrier samples
+    pub fn len(&self) -> usize {
+        self.pixels * self.channels.len()
+    }
+
+    // Index into the samples of the image of the carrier sample at the given position
+    pub fn sample(&self, position: usize) -> usize {
+        let carrier_pixel = position / self.channels.len();
+        let channel = self.channels[position % self.channels.len()];
+
+        let pixel = match &self.runs {
+            Some(runs) => {
+                let run = runs[runs.partition_point(|run| run.offset <= carrier_pixel) - 1];
+                run.pixel + carrier_pixel - run.offset
+            }
+            None => carrier_pixel,
+        };
+
+        pixel * self.channel_count + channel
+    }
+}
+
+// Role of each channel of the colour type, grayscale images have a single colour channel
+fn channel_roles(color_type: ColorType) -> &'static [ChannelMask] {
+    match color_type.channel_count() {
+        1 => &[ChannelMask::COLOUR],
+        2 => &[ChannelMask::COLOUR, ChannelMask::ALPHA],
+        3 => &[ChannelMask::RED, ChannelMask::GREEN, ChannelMask::BLUE],
+        _ => &[
+            ChannelMask::RED,
+            ChannelMask::GREEN,
+            ChannelMask::BLUE,
+            ChannelMask::ALPHA,
+        ],
+    }
+}
+
+// Channels of each pixel that carry data. The alpha channel of transparent pixels has to stay
+// untouched, it is how the decoder finds them again
+pub fn carrier_channels(
+    color_type: ColorType,
+    mask: ChannelMask,
+    transparent_pixels: bool,
+) -> Vec<usize> {
+    channel_roles(color_type)
+        .iter()
+        .enumerate()
+        .filter(|(_, role)| mask.intersects(**role))
+        .filter(|(_, role)| !(transparent_pixels && **role == ChannelMask::ALPHA))
+        .map(|(channel, _)| channel)
+        .collect()
+}
+
+// Runs of pixels whose alpha is 0, as (first pixel, length)
+fn transparent_runs<S: Copy + Default + PartialEq>(
+    samples: &[S],
+    color_type: ColorType,
+) -> Vec<(usize, usize)> {
+    if !color_type.has_alpha() {
+        return Vec::new();
+    }
+
+    let channel_count = color_type.channel_count() as usize;
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (pixel, samples) in samples.chunks_exact(channel_count).enumerate() {
+        if samples[channel_count - 1] != S::default() {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some((first, len)) if *first + *len == pixel => *len += 1,
+            _ => runs.push((pixel, 1)),
+        }
+    }
+    runs
+}
+
+// Every distinct layout an image of the colour type can carry data in, the default one first
+pub fn candidates(color_type: ColorType) -> Vec<(ChannelMask, bool)> {
+    let mut seen: Vec<(Vec<usize>, bool)> = Vec::new();
+    let mut candidates = Vec::new();
+
+    for transparent_pixels in [false, true] {
+        if transparent_pixels && !color_type.has_alpha() {
+            continue;
+        }
+
+        for mask in (0x1..=0xF).rev() {
+            let mask = ChannelMask(mask);
+            let channels = carrier_channels(color_type, mask, transparent_pixels);
+            let layout = (channels, transparent_pixels);
+            if layout.0.is_empty() || seen.contains(&layout) {
+                continue;
+            }
+
+            seen.push(layout);
+            candidates.push((mask, transparent_pixels));
+        }
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_carrier_channels() {
+        let channels =
+            |color_type, mask, transparent| carrier_channels(color_type, mask, transparent);
+
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            channels(ColorType::Rgba8, ChannelMask::ALL, false)
+        );
+        assert_eq!(
+            vec![0, 1, 2],
+            channels(ColorType::Rgba8, ChannelMask::ALL, true)
+        );
+        assert_eq!(
+            vec![2],
+            channels(ColorType::Rgb16, ChannelMask::BLUE, false)
+        );
+        assert_eq!(vec![0], channels(ColorType::La8, ChannelMask::BLUE, false));
+        assert!(channels(ColorType::L8, ChannelMask::ALPHA, false).is_empty());
+
+        assert_eq!((ChannelMask::ALL, false), candidates(ColorType::Rgba8)[0]);
+        assert_eq!(15 + 7, candidates(ColorType::Rgba8).len());
+        assert_eq!(1, candidates(ColorType::L8).len());
+    }
+
+    #[test]
+    fn test_layout_samples() {
+        // Pixels 1, 2 and 4 are transparent
+        let samples: Vec<u8> = vec![1, 9, 1, 0, 1, 0, 1, 9, 1, 0];
+        let positions = |layout: &Layout| -> Vec<usize> {
+            (0..layout.len()).map(|p| layout.sample(p)).collect()
+        };
+
+        let layout = Layout::new(&samples, ColorType::La8, ChannelMask::ALL, false);
+        assert_eq!((0..10).collect::<Vec<usize>>(), positions(&layout));
+
+        let layout = Layout::new(&samples, ColorType::La8, ChannelMask::ALPHA, false);
+        assert_eq!(vec![1, 3, 5, 7, 9], positions(&layout));
+
+        let layout = Layout::new(&samples, ColorType::La8, ChannelMask::ALL, true);
+        assert_eq!(vec![2, 4, 8], positions(&layout));
+    }
+}
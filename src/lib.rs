@@ -1,12 +1,48 @@
+pub mod analysis;
+mod chunks;
+mod compression;
+mod crypto;
 pub mod decoder;
+mod digest;
 pub mod encoder;
-
+pub mod error;
+mod fec;
+mod format;
+mod gf256;
+mod layout;
+pub mod metadata;
+pub mod quality;
+mod scatter;
+mod shamir;
+
+use error::StegError;
 use std::convert::TryFrom;
 use std::convert::TryInto;
-
-const VERSION: u8 = 0x1;
+use std::ops::BitOr;
+
+// 0x1: fixed header, data hash computed with DefaultHasher
+// 0x2: fixed header followed by extension records
+// 0x3: data hash is a CRC32C checksum, optionally backed by a strong digest record
+// 0x4: the authentication tag of an encrypted payload covers the header, and the metadata is
+//      sealed along with the payload instead of written to the header
+const VERSION: u8 = 0x4;
 const MAGIC: u16 = 0xBEAD;
 const HEADER_LENGTH: usize = 40;
+// Number of nibbles holding the byte length of the extension records in a version 2 header
+const EXTENSIONS_LENGTH: usize = 4;
+
+const EXTENSION_ENCRYPTION: u8 = 0x1;
+const EXTENSION_DIGEST: u8 = 0x2;
+const EXTENSION_FILE_NAME: u8 = 0x3;
+const EXTENSION_MIME_TYPE: u8 = 0x4;
+const EXTENSION_MODIFIED: u8 = 0x5;
+const EXTENSION_TAG: u8 = 0x6;
+const EXTENSION_ERROR_CORRECTION: u8 = 0x7;
+const EXTENSION_EMBEDDING_MODE: u8 = 0x8;
+const EXTENSION_CHANNELS: u8 = 0x9;
+const EXTENSION_FRAGMENT: u8 = 0xA;
+const EXTENSION_SHARE: u8 = 0xB;
+const EXTENSION_COMPRESSION_LEVEL: u8 = 0xC;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ByteSplitGranularity {
@@ -15,6 +51,22 @@ pub enum ByteSplitGranularity {
     OneBit,
 }
 
+impl ByteSplitGranularity {
+    // Number of cover image bytes needed to carry a single byte of data
+    fn parts_per_byte(self) -> usize {
+        match self {
+            ByteSplitGranularity::FourBits => 2,
+            ByteSplitGranularity::TwoBits => 4,
+            ByteSplitGranularity::OneBit => 8,
+        }
+    }
+
+    // Number of low bits of every cover image byte that carry data
+    fn bits(self) -> u32 {
+        8 / self.parts_per_byte() as u32
+    }
+}
+
 impl TryFrom<u8> for ByteSplitGranularity {
     type Error = String;
 
@@ -28,9 +80,9 @@ impl TryFrom<u8> for ByteSplitGranularity {
     }
 }
 
-impl Into<u8> for ByteSplitGranularity {
-    fn into(self) -> u8 {
-        match self {
+impl From<ByteSplitGranularity> for u8 {
+    fn from(granularity: ByteSplitGranularity) -> u8 {
+        match granularity {
             ByteSplitGranularity::FourBits => 4,
             ByteSplitGranularity::TwoBits => 2,
             ByteSplitGranularity::OneBit => 1,
@@ -38,306 +90,1206 @@ impl Into<u8> for ByteSplitGranularity {
     }
 }
 
+// Codec the data is compressed with before it is embedded, along with its level. Gzip uses the
+// default level of flate2. Auto compresses the data with each codec at its highest level and
+// keeps the smallest result, or leaves the data as it is when none makes it smaller. The header
+// records the codec that was used
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum CompressInput {
     None,
     Gzip,
+    // Levels from zstd::compression_level_range, -131072 (fastest) to 22, 3 by default. Levels
+    // outside of it are clamped
+    Zstd(i32),
+    // Quality from 0 to 11, 11 by default
+    Brotli(u32),
+    // Presets from 0 to 9, 6 by default
+    Xz(u32),
+    Auto,
+}
+
+impl CompressInput {
+    pub const ZSTD_DEFAULT_LEVEL: i32 = 3;
+    pub const BROTLI_DEFAULT_LEVEL: u32 = 11;
+    pub const XZ_DEFAULT_LEVEL: u32 = 6;
+
+    // The level, for the codecs that have one
+    fn level(self) -> Option<i32> {
+        match self {
+            CompressInput::Zstd(level) => Some(level),
+            CompressInput::Brotli(level) | CompressInput::Xz(level) => Some(level as i32),
+            _ => None,
+        }
+    }
+
+    fn with_level(self, level: i32) -> Result<Self, String> {
+        match self {
+            CompressInput::Zstd(_) => Ok(CompressInput::Zstd(level)),
+            CompressInput::Brotli(_) if level >= 0 => Ok(CompressInput::Brotli(level as u32)),
+            CompressInput::Xz(_) if level >= 0 => Ok(CompressInput::Xz(level as u32)),
+            _ => Err(format!("invalid level {} for {:?}", level, self)),
+        }
+    }
 }
 
 impl TryFrom<u8> for CompressInput {
     type Error = String;
 
+    // Codecs with a level get their default level, the header stores other levels separately
     fn try_from(v: u8) -> Result<Self, Self::Error> {
         match v {
             0 => Ok(CompressInput::None),
             1 => Ok(CompressInput::Gzip),
+            2 => Ok(CompressInput::Zstd(CompressInput::ZSTD_DEFAULT_LEVEL)),
+            3 => Ok(CompressInput::Brotli(CompressInput::BROTLI_DEFAULT_LEVEL)),
+            4 => Ok(CompressInput::Xz(CompressInput::XZ_DEFAULT_LEVEL)),
             _ => Err("Unsupported value for CompressInput".to_string()),
         }
     }
 }
 
-impl Into<u8> for CompressInput {
-    fn into(self) -> u8 {
-        match self {
+// Auto is resolved to a codec before the header is written, the value it maps to is rejected
+// when it is read back
+impl From<CompressInput> for u8 {
+    fn from(compress_input: CompressInput) -> u8 {
+        match compress_input {
             CompressInput::None => 0,
             CompressInput::Gzip => 1,
+            CompressInput::Zstd(_) => 2,
+            CompressInput::Brotli(_) => 3,
+            CompressInput::Xz(_) => 4,
+            CompressInput::Auto => 0xF,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct DataHeader {
-    magic: u16,
-    version: u8,
-    bytes_count: u64,
-    data_hash: u64,
-    compress_input: CompressInput,
-    granularity: ByteSplitGranularity,
+// Algorithm used to check the integrity of the embedded data. Crc32c is a fast check against
+// corruption, Blake3 and Sha256 additionally store a 256 bit digest in the header
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DigestKind {
+    Crc32c,
+    Blake3,
+    Sha256,
 }
 
-impl DataHeader {
-    pub fn new(compress_input: CompressInput, granularity: ByteSplitGranularity) -> Self {
-        Self {
-            magic: MAGIC,
-            version: VERSION,
-            bytes_count: 0,
-            data_hash: 0,
-            compress_input,
-            granularity,
+impl TryFrom<u8> for DigestKind {
+    type Error = String;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(DigestKind::Crc32c),
+            1 => Ok(DigestKind::Blake3),
+            2 => Ok(DigestKind::Sha256),
+            _ => Err("Unsupported value for DigestKind".to_string()),
         }
     }
 }
 
-impl Default for DataHeader {
-    fn default() -> Self {
-        Self::new(CompressInput::None, ByteSplitGranularity::FourBits)
+impl From<DigestKind> for u8 {
+    fn from(kind: DigestKind) -> u8 {
+        match kind {
+            DigestKind::Crc32c => 0,
+            DigestKind::Blake3 => 1,
+            DigestKind::Sha256 => 2,
+        }
     }
 }
 
-impl TryFrom<[u8; HEADER_LENGTH]> for DataHeader {
+// How the low bits of the cover image samples are changed to carry the data. Replacement
+// overwrites them, which leaves the pairs of values artefact in the histogram that chi-square and
+// RS steganalysis detect. Matching adds to or subtracts from the sample instead, picking the
+// direction at random when both are as close
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EmbeddingMode {
+    Replacement,
+    Matching,
+}
+
+impl TryFrom<u8> for EmbeddingMode {
     type Error = String;
-    fn try_from(data: [u8; HEADER_LENGTH]) -> Result<Self, Self::Error> {
-        let mut magic_expanded: [u8; 16] = [0; 16];
-        magic_expanded[12..16].clone_from_slice(&data[0..4]);
 
-        let magic: u64 = NibbleNumber::new(magic_expanded).into();
-        let magic: u16 = magic as u16;
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(EmbeddingMode::Replacement),
+            1 => Ok(EmbeddingMode::Matching),
+            _ => Err("Unsupported value for EmbeddingMode".to_string()),
+        }
+    }
+}
 
-        if magic != MAGIC {
-            return Err(format!("invalid magic: {:#x}", magic));
+impl From<EmbeddingMode> for u8 {
+    fn from(mode: EmbeddingMode) -> u8 {
+        match mode {
+            EmbeddingMode::Replacement => 0,
+            EmbeddingMode::Matching => 1,
         }
+    }
+}
 
-        let mut version_expanded: [u8; 16] = [0; 16];
-        version_expanded[14..16].clone_from_slice(&data[4..6]);
+// Channels of the cover image that carry the data. Channels are named by their role so that the
+// same mask applies to every colour type, the luma channel of a grayscale image carries data when
+// any of the colour channels does
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ChannelMask(u8);
+
+impl ChannelMask {
+    pub const RED: ChannelMask = ChannelMask(0x1);
+    pub const GREEN: ChannelMask = ChannelMask(0x2);
+    pub const BLUE: ChannelMask = ChannelMask(0x4);
+    pub const ALPHA: ChannelMask = ChannelMask(0x8);
+    pub const COLOUR: ChannelMask = ChannelMask(0x7);
+    pub const ALL: ChannelMask = ChannelMask(0xF);
+
+    pub fn contains(self, other: ChannelMask) -> bool {
+        self.0 & other.0 == other.0
+    }
 
-        let version: u64 = NibbleNumber::new(version_expanded).into();
-        let version: u8 = version as u8;
+    pub fn intersects(self, other: ChannelMask) -> bool {
+        self.0 & other.0 != 0
+    }
+}
 
-        if version != VERSION {
-            return Err(format!("unsupported version: {:#x}", version));
+impl BitOr for ChannelMask {
+    type Output = ChannelMask;
+
+    fn bitor(self, other: ChannelMask) -> ChannelMask {
+        ChannelMask(self.0 | other.0)
+    }
+}
+
+impl TryFrom<u8> for ChannelMask {
+    type Error = String;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0x1..=0xF => Ok(ChannelMask(v)),
+            _ => Err("Unsupported value for ChannelMask".to_string()),
         }
+    }
+}
 
-        let bytes_count: u64 = NibbleNumber::new(data[6..22].to_vec().try_into().unwrap()).into();
-        let data_hash: u64 = NibbleNumber::new(data[22..38].to_vec().try_into().unwrap()).into();
+impl From<ChannelMask> for u8 {
+    fn from(mask: ChannelMask) -> u8 {
+        mask.0
+    }
+}
 
-        let mut compressed_expanded: [u8; 16] = [0; 16];
-        compressed_expanded[15..16].clone_from_slice(&data[38..39]);
+// Reed-Solomon error correction of the embedded data. The data is cut into blocks of
+// block_length bytes, parity_length of which are parity bytes that can repair up to
+// parity_length / 2 corrupted bytes of the block
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ErrorCorrection {
+    block_length: u8,
+    parity_length: u8,
+}
 
-        let compressed_expanded: u64 = NibbleNumber::new(compressed_expanded).into();
-        let compress_input = CompressInput::try_from(compressed_expanded as u8)?;
+impl ErrorCorrection {
+    // Blocks are as long as the code allows, redundancy is the share of parity bytes in each of
+    // them. It is clamped so that blocks hold at least 2 parity bytes and 1 byte of data
+    pub fn new(redundancy: f64) -> Self {
+        let block_length = u8::MAX;
+        let parity_length = (redundancy * block_length as f64)
+            .round()
+            .max(2.0)
+            .min(block_length as f64 - 1.0) as u8;
 
-        let mut granularity_expanded: [u8; 16] = [0; 16];
-        granularity_expanded[15..16].clone_from_slice(&data[39..40]);
+        Self {
+            block_length,
+            parity_length,
+        }
+    }
 
-        let granularity_expanded: u64 = NibbleNumber::new(granularity_expanded).into();
-        let granularity = ByteSplitGranularity::try_from(granularity_expanded as u8)?;
+    pub fn block_length(&self) -> usize {
+        self.block_length as usize
+    }
 
-        Ok(DataHeader {
-            magic,
-            version,
-            bytes_count,
-            data_hash,
-            compress_input,
-            granularity,
-        })
+    pub fn parity_length(&self) -> usize {
+        self.parity_length as usize
     }
-}
 
-impl Into<[u8; HEADER_LENGTH]> for DataHeader {
-    fn into(self) -> [u8; HEADER_LENGTH] {
-        let magic = NibbleNumber::from(self.magic as u64);
-        let version = NibbleNumber::from(self.version as u64);
-        let bytes_count = NibbleNumber::from(self.bytes_count);
-        let hash = NibbleNumber::from(self.data_hash);
+    pub fn data_length(&self) -> usize {
+        self.block_length() - self.parity_length()
+    }
 
-        let compress_input: u8 = self.compress_input.into();
-        let compress_input = NibbleNumber::from(compress_input as u64);
-        let granularity: u8 = self.granularity.into();
-        let granularity = NibbleNumber::from(granularity as u64);
+    // Number of corrupted bytes that can be repaired in every block
+    pub fn max_corrections(&self) -> usize {
+        self.parity_length() / 2
+    }
 
-        let mut raw: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+    fn to_bytes(self) -> Vec<u8> {
+        vec![self.block_length, self.parity_length]
+    }
 
-        raw[..4].clone_from_slice(&magic.data[12..16]);
-        raw[4..6].clone_from_slice(&version.data[14..16]);
-        raw[6..22].clone_from_slice(&bytes_count.data);
-        raw[22..38].clone_from_slice(&hash.data);
-        raw[38..39].clone_from_slice(&compress_input.data[15..16]);
-        raw[39..40].clone_from_slice(&granularity.data[15..16]);
-        raw
+    fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        match *data {
+            [block_length, parity_length] if parity_length > 0 && parity_length < block_length => {
+                Ok(Self {
+                    block_length,
+                    parity_length,
+                })
+            }
+            _ => Err("invalid error correction parameters".to_string()),
+        }
     }
 }
 
-fn err_to_io_error<E>(error: E) -> std::io::Error
-where
-    E: Into<Box<dyn std::error::Error + Send + Sync>>,
-{
-    std::io::Error::new(std::io::ErrorKind::Other, error.into())
+// Where the data carried by an image belongs when a payload is split across several images. All
+// fragments of a payload share its randomly picked message ID
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Fragment {
+    message_id: u64,
+    index: u16,
+    count: u16,
 }
 
-#[derive(Debug)]
-struct NibbleNumber {
-    data: [u8; 16],
-}
-impl NibbleNumber {
-    fn new(data: [u8; 16]) -> Self {
-        Self { data }
+impl Fragment {
+    pub fn message_id(&self) -> u64 {
+        self.message_id
     }
-}
 
-impl From<usize> for NibbleNumber {
-    fn from(value: usize) -> Self {
-        NibbleNumber::from(value as u64)
+    pub fn index(&self) -> usize {
+        self.index as usize
     }
-}
 
-impl From<u64> for NibbleNumber {
-    fn from(value: u64) -> Self {
-        let result: [u8; 16] = [
-            ((value & 0xF000000000000000) >> 60) as u8,
-            ((value & 0x0F00000000000000) >> 56) as u8,
-            ((value & 0x00F0000000000000) >> 52) as u8,
-            ((value & 0x000F000000000000) >> 48) as u8,
-            ((value & 0x0000F00000000000) >> 44) as u8,
-            ((value & 0x00000F0000000000) >> 40) as u8,
-            ((value & 0x000000F000000000) >> 36) as u8,
-            ((value & 0x0000000F00000000) >> 32) as u8,
-            ((value & 0x00000000F0000000) >> 28) as u8,
-            ((value & 0x000000000F000000) >> 24) as u8,
-            ((value & 0x0000000000F00000) >> 20) as u8,
-            ((value & 0x00000000000F0000) >> 16) as u8,
-            ((value & 0x000000000000F000) >> 12) as u8,
-            ((value & 0x0000000000000F00) >> 8) as u8,
-            ((value & 0x00000000000000F0) >> 4) as u8,
-            (value & 0x000000000000000F) as u8,
-        ];
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
 
-        NibbleNumber { data: result }
+    fn to_bytes(self) -> Vec<u8> {
+        let mut out = self.message_id.to_be_bytes().to_vec();
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.count.to_be_bytes());
+        out
     }
-}
 
-impl Into<u64> for NibbleNumber {
-    fn into(self) -> u64 {
-        let value_count: u64 = (self.data[0] as u64) << 60
-            | (self.data[1] as u64) << 56
-            | (self.data[2] as u64) << 52
-            | (self.data[3] as u64) << 48
-            | (self.data[4] as u64) << 44
-            | (self.data[5] as u64) << 40
-            | (self.data[6] as u64) << 36
-            | (self.data[7] as u64) << 32
-            | (self.data[8] as u64) << 28
-            | (self.data[9] as u64) << 24
-            | (self.data[10] as u64) << 20
-            | (self.data[11] as u64) << 16
-            | (self.data[12] as u64) << 12
-            | (self.data[13] as u64) << 8
-            | (self.data[14] as u64) << 4
-            | (self.data[15] as u64);
+    fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() != 12 {
+            return Err("invalid fragment length".to_string());
+        }
 
-        value_count
+        let fragment = Self {
+            message_id: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            index: u16::from_be_bytes([data[8], data[9]]),
+            count: u16::from_be_bytes([data[10], data[11]]),
+        };
+
+        if fragment.index >= fragment.count {
+            return Err(format!(
+                "fragment index {} is out of range of {} fragments",
+                fragment.index, fragment.count
+            ));
+        }
+        Ok(fragment)
     }
 }
 
-mod byte_encodings {
-    use super::ByteSplitGranularity;
+// Which share of a secret an image carries when the payload was split with Shamir's secret
+// sharing. Any threshold of the shares with the same secret ID reconstruct the payload
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Share {
+    secret_id: u64,
+    index: u8,
+    threshold: u8,
+    count: u8,
+}
 
-    pub struct BytesZipper {}
+impl Share {
+    pub fn secret_id(&self) -> u64 {
+        self.secret_id
+    }
 
-    impl BytesZipper {
-        pub fn merge_into(dest: &mut [u8], src: &[u8], granularity: ByteSplitGranularity) {
-            dest.iter_mut().zip(src).for_each(|(left, right)| {
-                *left = zip_bytes(granularity, left.clone(), *right);
-            });
-        }
+    pub fn index(&self) -> usize {
+        self.index as usize
     }
 
-    pub fn split_byte(granularity: ByteSplitGranularity, byte: u8) -> Vec<u8> {
-        match granularity {
-            ByteSplitGranularity::FourBits => vec![byte >> 4, byte & 0x0F],
-            ByteSplitGranularity::TwoBits => vec![
-                (byte >> 6) & 0x03,
-                (byte >> 4) & 0x03,
-                (byte >> 2) & 0x03,
-                byte & 0x03,
-            ],
-            ByteSplitGranularity::OneBit => vec![
-                (byte >> 7) & 0x01,
-                (byte >> 6) & 0x01,
-                (byte >> 5) & 0x01,
-                (byte >> 4) & 0x01,
-                (byte >> 3) & 0x01,
-                (byte >> 2) & 0x01,
-                (byte >> 1) & 0x01,
-                byte & 0x01,
-            ],
-        }
+    // Number of shares needed to reconstruct the payload
+    pub fn threshold(&self) -> usize {
+        self.threshold as usize
     }
 
-    pub fn zip_bytes(granularity: ByteSplitGranularity, left: u8, right: u8) -> u8 {
-        match granularity {
-            ByteSplitGranularity::FourBits => (left & 0xF0) | (right & 0x0F),
-            ByteSplitGranularity::TwoBits => (left & 0xFC) | (right & 0x03),
-            ByteSplitGranularity::OneBit => (left & 0xFE) | (right & 0x01),
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut out = self.secret_id.to_be_bytes().to_vec();
+        out.extend_from_slice(&[self.index, self.threshold, self.count]);
+        out
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() != 11 {
+            return Err("invalid share length".to_string());
         }
+
+        let share = Self {
+            secret_id: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            index: data[8],
+            threshold: data[9],
+            count: data[10],
+        };
+
+        if share.index >= share.count || share.threshold == 0 || share.threshold > share.count {
+            return Err(format!(
+                "share {} with a threshold of {} is out of range of {} shares",
+                share.index, share.threshold, share.count
+            ));
+        }
+        Ok(share)
     }
+}
 
-    // FourBits = bottom 4 bits
-    // TwoBits = bottom 2 bits
-    pub fn merge_bytes(granularity: ByteSplitGranularity, bytes: &[u8]) -> u8 {
-        match granularity {
-            ByteSplitGranularity::FourBits => ((bytes[0] << 4) & 0xF0) | (bytes[1] & 0x0F),
-            ByteSplitGranularity::TwoBits => {
-                (bytes[0] << 6) & 0xC0
-                    | (bytes[1] << 4) & 0x30
-                    | (bytes[2] << 2) & 0x0C
-                    | (bytes[3] & 0x03)
-            }
-            ByteSplitGranularity::OneBit => {
-                (bytes[0] << 7) & 0x80
-                    | (bytes[1] << 6) & 0x40
-                    | (bytes[2] << 5) & 0x20
-                    | (bytes[3] << 4) & 0x10
-                    | (bytes[4] << 3) & 0x08
-                    | (bytes[5] << 2) & 0x04
-                    | (bytes[6] << 1) & 0x02
-                    | (bytes[7] & 0x01)
-            }
+#[derive(Debug, Clone)]
+pub struct DataHeader {
+    magic: u16,
+    version: u8,
+    bytes_count: u64,
+    data_hash: u64,
+    compress_input: CompressInput,
+    granularity: ByteSplitGranularity,
+    encryption: Option<crypto::EncryptionParams>,
+    digest: Option<digest::Digest>,
+    error_correction: Option<ErrorCorrection>,
+    embedding_mode: EmbeddingMode,
+    channel_mask: ChannelMask,
+    transparent_pixels: bool,
+    fragment: Option<Fragment>,
+    share: Option<Share>,
+    metadata: metadata::Metadata,
+}
+
+impl DataHeader {
+    pub fn new(compress_input: CompressInput, granularity: ByteSplitGranularity) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            bytes_count: 0,
+            data_hash: 0,
+            compress_input,
+            granularity,
+            encryption: None,
+            digest: None,
+            error_correction: None,
+            embedding_mode: EmbeddingMode::Replacement,
+            channel_mask: ChannelMask::ALL,
+            transparent_pixels: false,
+            fragment: None,
+            share: None,
+            metadata: metadata::Metadata::default(),
         }
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::super::ByteSplitGranularity;
-        use super::*;
+    pub fn magic(&self) -> u16 {
+        self.magic
+    }
 
-        #[test]
-        fn test_bytes_zipper_one_bit() {
-            let mut dest: Vec<u8> = vec![0xFE, 0xFE];
-            let src: Vec<u8> = vec![0x01, 0x01];
+    pub fn version(&self) -> u8 {
+        self.version
+    }
 
-            BytesZipper::merge_into(&mut dest, &src, ByteSplitGranularity::OneBit);
-            assert_eq!(vec![0xFF, 0xFF], dest);
-        }
+    // Number of bytes embedded in the image, after compression, encryption and error correction
+    pub fn bytes_count(&self) -> u64 {
+        self.bytes_count
+    }
 
-        #[test]
-        fn test_bytes_zipper_two_bits() {
-            let mut dest: Vec<u8> = vec![0xFC, 0xFC];
-            let src: Vec<u8> = vec![0x03, 0x03];
+    pub fn compress_input(&self) -> CompressInput {
+        self.compress_input
+    }
 
-            BytesZipper::merge_into(&mut dest, &src, ByteSplitGranularity::TwoBits);
-            assert_eq!(vec![0xFF, 0xFF], dest);
+    pub fn granularity(&self) -> ByteSplitGranularity {
+        self.granularity
+    }
+
+    // Integrity check used for the data, None for headers before version 3 which use
+    // DefaultHasher
+    pub fn digest_kind(&self) -> Option<DigestKind> {
+        if self.version < 0x3 {
+            return None;
         }
 
-        #[test]
-        fn test_bytes_zipper_four_bits() {
-            let mut dest: Vec<u8> = vec![0xFF, 0xFF];
-            let src: Vec<u8> = vec![0x07, 0x07];
+        Some(self.digest.map_or(DigestKind::Crc32c, |digest| digest.kind))
+    }
 
-            BytesZipper::merge_into(&mut dest, &src, ByteSplitGranularity::FourBits);
-            assert_eq!(vec![0xF7, 0xF7], dest);
-        }
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
 
-        #[test]
+    pub fn error_correction(&self) -> Option<ErrorCorrection> {
+        self.error_correction
+    }
+
+    pub fn embedding_mode(&self) -> EmbeddingMode {
+        self.embedding_mode
+    }
+
+    pub fn channel_mask(&self) -> ChannelMask {
+        self.channel_mask
+    }
+
+    // Whether only the fully transparent pixels of the image carry data
+    pub fn transparent_pixels(&self) -> bool {
+        self.transparent_pixels
+    }
+
+    // Where the data belongs, None unless the payload was split across several images
+    pub fn fragment(&self) -> Option<Fragment> {
+        self.fragment
+    }
+
+    // Which share of a secret the data is, None unless the payload was split into shares
+    pub fn share(&self) -> Option<Share> {
+        self.share
+    }
+
+    pub fn metadata(&self) -> &metadata::Metadata {
+        &self.metadata
+    }
+
+    // Serialises the header into nibbles, ready to be merged into the cover image.
+    // Version 2 headers are followed by the length of the extension records and the records themselves
+    fn to_nibbles(&self) -> Result<Vec<u8>, StegError> {
+        let raw: [u8; HEADER_LENGTH] = self.clone().into();
+        let mut nibbles = raw.to_vec();
+
+        if self.version >= 0x2 {
+            let extensions = self.extensions_to_bytes()?;
+            if extensions.len() > u16::MAX as usize {
+                return Err(StegError::InvalidMetadata(format!(
+                    "header extensions are too large: {} bytes",
+                    extensions.len()
+                )));
+            }
+
+            let extensions_length = NibbleNumber::from(extensions.len());
+            nibbles.extend_from_slice(&extensions_length.data[16 - EXTENSIONS_LENGTH..]);
+
+            for b in extensions {
+                nibbles.extend(byte_encodings::split_byte(
+                    ByteSplitGranularity::FourBits,
+                    b,
+                ));
+            }
+        }
+
+        Ok(nibbles)
+    }
+
+    // Reads a header from the low nibbles of the given bytes, returning it along with the number of
+    // bytes it occupies
+    fn read_from(input: &[u8]) -> Result<(Self, usize), StegError> {
+        let header_length = Self::read_length(input)?;
+        let mut header = Self::read_fixed(input)?;
+
+        if input.len() < header_length {
+            return Err(StegError::InvalidHeader(
+                "image header is truncated".to_string(),
+            ));
+        }
+
+        if header.version >= 0x2 {
+            let extensions: Vec<u8> = input[HEADER_LENGTH + EXTENSIONS_LENGTH..header_length]
+                .chunks(2)
+                .map(|chunk| byte_encodings::merge_bytes(ByteSplitGranularity::FourBits, chunk))
+                .collect();
+
+            header
+                .read_extensions(&extensions)
+                .map_err(StegError::InvalidHeader)?;
+        }
+
+        Ok((header, header_length))
+    }
+
+    // Number of bytes occupied by the header at the start of input. This only needs the fixed part
+    // of the header and, for version 2 headers, the extensions length to be present
+    fn read_length(input: &[u8]) -> Result<usize, StegError> {
+        let header = Self::read_fixed(input)?;
+        if header.version < 0x2 {
+            return Ok(HEADER_LENGTH);
+        }
+
+        let extensions_start = HEADER_LENGTH + EXTENSIONS_LENGTH;
+        if input.len() < extensions_start {
+            return Err(StegError::InvalidHeader(
+                "image header is truncated".to_string(),
+            ));
+        }
+
+        let mut extensions_length_expanded: [u8; 16] = [0; 16];
+        input[HEADER_LENGTH..extensions_start]
+            .iter()
+            .zip(extensions_length_expanded[16 - EXTENSIONS_LENGTH..].iter_mut())
+            .for_each(|(src, dest)| *dest = src & 0x0F);
+
+        let extensions_length: u64 = NibbleNumber::new(extensions_length_expanded).into();
+        Ok(extensions_start + (extensions_length as usize * 2))
+    }
+
+    // Whether the low nibbles of the given bytes start with the header magic
+    fn has_magic(input: &[u8]) -> bool {
+        if input.len() < 4 {
+            return false;
+        }
+
+        let mut magic_expanded: [u8; 16] = [0; 16];
+        input[..4]
+            .iter()
+            .zip(magic_expanded[12..16].iter_mut())
+            .for_each(|(src, dest)| *dest = src & 0x0F);
+
+        let magic: u64 = NibbleNumber::new(magic_expanded).into();
+        magic as u16 == MAGIC
+    }
+
+    fn read_fixed(input: &[u8]) -> Result<Self, StegError> {
+        if input.len() < HEADER_LENGTH {
+            return Err(StegError::InvalidHeader(
+                "image header is not present".to_string(),
+            ));
+        }
+
+        let mut raw_header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+        raw_header.copy_from_slice(&input[..HEADER_LENGTH]);
+        raw_header.iter_mut().for_each(|x| *x &= 0x0F);
+
+        DataHeader::try_from(raw_header)
+    }
+
+    // The header as covered by the authentication tag of an encrypted payload. Fields that are
+    // only set once the sealed payload is embedded, or that differ between the images it is split
+    // across, are left out. Headers before version 4 are not covered
+    fn associated_data(&self) -> Result<Vec<u8>, StegError> {
+        if self.version < 0x4 {
+            return Ok(Vec::new());
+        }
+
+        let mut header = self.clone();
+        header.bytes_count = 0;
+        header.data_hash = 0;
+        header.granularity = ByteSplitGranularity::FourBits;
+        header.digest = None;
+        header.error_correction = None;
+        header.fragment = None;
+        header.share = None;
+
+        let raw: [u8; HEADER_LENGTH] = header.clone().into();
+        let mut out = raw.to_vec();
+        out.extend(header.extensions_to_bytes()?);
+        Ok(out)
+    }
+
+    // Extension records are stored as [tag: u8][length: u16][value]
+    fn extensions_to_bytes(&self) -> Result<Vec<u8>, StegError> {
+        let mut out = Vec::new();
+
+        if let Some(encryption) = self.encryption {
+            write_extension(&mut out, EXTENSION_ENCRYPTION, &encryption.to_bytes())?;
+        }
+
+        if let Some(digest) = self.digest {
+            write_extension(&mut out, EXTENSION_DIGEST, &digest.to_bytes())?;
+        }
+
+        // Only levels other than the codec's default, the decoder does not need it either way
+        if let Some(level) = self.compress_input.level() {
+            if CompressInput::try_from(u8::from(self.compress_input)) != Ok(self.compress_input) {
+                write_extension(&mut out, EXTENSION_COMPRESSION_LEVEL, &level.to_be_bytes())?;
+            }
+        }
+
+        if let Some(error_correction) = self.error_correction {
+            write_extension(
+                &mut out,
+                EXTENSION_ERROR_CORRECTION,
+                &error_correction.to_bytes(),
+            )?;
+        }
+
+        if self.embedding_mode != EmbeddingMode::Replacement {
+            write_extension(
+                &mut out,
+                EXTENSION_EMBEDDING_MODE,
+                &[self.embedding_mode.into()],
+            )?;
+        }
+
+        if self.channel_mask != ChannelMask::ALL || self.transparent_pixels {
+            write_extension(
+                &mut out,
+                EXTENSION_CHANNELS,
+                &[self.channel_mask.into(), self.transparent_pixels as u8],
+            )?;
+        }
+
+        if let Some(fragment) = self.fragment {
+            write_extension(&mut out, EXTENSION_FRAGMENT, &fragment.to_bytes())?;
+        }
+
+        if let Some(share) = self.share {
+            write_extension(&mut out, EXTENSION_SHARE, &share.to_bytes())?;
+        }
+
+        write_metadata(&mut out, &self.metadata)?;
+
+        Ok(out)
+    }
+
+    fn read_extensions(&mut self, data: &[u8]) -> Result<(), String> {
+        read_records(data, |tag, value| {
+            match tag {
+                EXTENSION_ENCRYPTION => {
+                    self.encryption = Some(crypto::EncryptionParams::from_bytes(value)?)
+                }
+                EXTENSION_DIGEST => self.digest = Some(digest::Digest::from_bytes(value)?),
+                EXTENSION_COMPRESSION_LEVEL => match *value {
+                    [a, b, c, d] => {
+                        let level = i32::from_be_bytes([a, b, c, d]);
+                        self.compress_input = self.compress_input.with_level(level)?;
+                    }
+                    _ => return Err("invalid compression level length".to_string()),
+                },
+                EXTENSION_ERROR_CORRECTION => {
+                    self.error_correction = Some(ErrorCorrection::from_bytes(value)?)
+                }
+                EXTENSION_EMBEDDING_MODE => match *value {
+                    [mode] => self.embedding_mode = EmbeddingMode::try_from(mode)?,
+                    _ => return Err("invalid embedding mode length".to_string()),
+                },
+                EXTENSION_CHANNELS => match *value {
+                    [mask, transparent_pixels @ (0 | 1)] => {
+                        self.channel_mask = ChannelMask::try_from(mask)?;
+                        self.transparent_pixels = transparent_pixels == 1;
+                    }
+                    _ => return Err("invalid channels record".to_string()),
+                },
+                EXTENSION_FRAGMENT => self.fragment = Some(Fragment::from_bytes(value)?),
+                EXTENSION_SHARE => self.share = Some(Share::from_bytes(value)?),
+                _ => read_metadata(&mut self.metadata, tag, value)?,
+            }
+
+            Ok(())
+        })
+    }
+}
+
+// Metadata of an encrypted payload is sealed along with it rather than written to the header in
+// the clear, as [length: u32][extension records] ahead of the data
+fn seal_metadata(metadata: &metadata::Metadata) -> Result<Vec<u8>, StegError> {
+    let mut records = Vec::new();
+    write_metadata(&mut records, metadata)?;
+    let length = u32::try_from(records.len()).map_err(|_| {
+        StegError::InvalidMetadata(format!("metadata is too large: {} bytes", records.len()))
+    })?;
+
+    let mut out = length.to_be_bytes().to_vec();
+    out.extend(records);
+    Ok(out)
+}
+
+// Reads the metadata sealed ahead of the data of a decrypted payload, returning it along with the
+// number of bytes it takes
+fn open_metadata(data: &[u8]) -> Result<(metadata::Metadata, usize), StegError> {
+    let truncated = || StegError::InvalidMetadata("sealed metadata is truncated".to_string());
+    let length = match data {
+        [a, b, c, d, ..] => u32::from_be_bytes([*a, *b, *c, *d]) as usize,
+        _ => return Err(truncated()),
+    };
+    let records = data.get(4..4 + length).ok_or_else(truncated)?;
+
+    let mut metadata = metadata::Metadata::default();
+    read_records(records, |tag, value| {
+        read_metadata(&mut metadata, tag, value)
+    })
+    .map_err(StegError::InvalidMetadata)?;
+    Ok((metadata, 4 + length))
+}
+
+fn write_metadata(out: &mut Vec<u8>, metadata: &metadata::Metadata) -> Result<(), StegError> {
+    if let Some(file_name) = &metadata.file_name {
+        write_extension(out, EXTENSION_FILE_NAME, file_name.as_bytes())?;
+    }
+
+    if let Some(mime_type) = &metadata.mime_type {
+        write_extension(out, EXTENSION_MIME_TYPE, mime_type.as_bytes())?;
+    }
+
+    if let Some(modified) = metadata.modified {
+        let modified = metadata::encode_modified(modified).map_err(StegError::InvalidMetadata)?;
+        write_extension(out, EXTENSION_MODIFIED, &modified)?;
+    }
+
+    for tag in &metadata.tags {
+        write_extension(out, EXTENSION_TAG, tag.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn read_metadata(metadata: &mut metadata::Metadata, tag: u8, value: &[u8]) -> Result<(), String> {
+    match tag {
+        EXTENSION_FILE_NAME => metadata.file_name = Some(metadata::decode_string(value)?),
+        EXTENSION_MIME_TYPE => metadata.mime_type = Some(metadata::decode_string(value)?),
+        EXTENSION_MODIFIED => metadata.modified = Some(metadata::decode_modified(value)?),
+        EXTENSION_TAG => metadata.tags.push(metadata::decode_string(value)?),
+        _ => return Err(format!("unsupported extension record: {:#x}", tag)),
+    }
+
+    Ok(())
+}
+
+// Calls read_record with the tag and value of every extension record in data
+fn read_records<F: FnMut(u8, &[u8]) -> Result<(), String>>(
+    data: &[u8],
+    mut read_record: F,
+) -> Result<(), String> {
+    let mut remaining = data;
+
+    while !remaining.is_empty() {
+        if remaining.len() < 3 {
+            return Err("truncated extension record".to_string());
+        }
+
+        let tag = remaining[0];
+        let length = u16::from_be_bytes([remaining[1], remaining[2]]) as usize;
+        remaining = &remaining[3..];
+
+        if remaining.len() < length {
+            return Err(format!("truncated extension record: {:#x}", tag));
+        }
+
+        read_record(tag, &remaining[..length])?;
+        remaining = &remaining[length..];
+    }
+
+    Ok(())
+}
+
+fn write_extension(out: &mut Vec<u8>, tag: u8, value: &[u8]) -> Result<(), StegError> {
+    if value.len() > u16::MAX as usize {
+        return Err(StegError::InvalidMetadata(format!(
+            "header extension {:#x} is too large: {} bytes",
+            tag,
+            value.len()
+        )));
+    }
+
+    out.push(tag);
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+    Ok(())
+}
+
+impl Default for DataHeader {
+    fn default() -> Self {
+        Self::new(CompressInput::None, ByteSplitGranularity::FourBits)
+    }
+}
+
+impl TryFrom<[u8; HEADER_LENGTH]> for DataHeader {
+    type Error = StegError;
+    fn try_from(data: [u8; HEADER_LENGTH]) -> Result<Self, Self::Error> {
+        let mut magic_expanded: [u8; 16] = [0; 16];
+        magic_expanded[12..16].clone_from_slice(&data[0..4]);
+
+        let magic: u64 = NibbleNumber::new(magic_expanded).into();
+        let magic: u16 = magic as u16;
+
+        if magic != MAGIC {
+            return Err(StegError::InvalidMagic(magic));
+        }
+
+        let mut version_expanded: [u8; 16] = [0; 16];
+        version_expanded[14..16].clone_from_slice(&data[4..6]);
+
+        let version: u64 = NibbleNumber::new(version_expanded).into();
+        let version: u8 = version as u8;
+
+        if version == 0 || version > VERSION {
+            return Err(StegError::UnsupportedVersion(version));
+        }
+
+        let bytes_count: u64 = NibbleNumber::new(data[6..22].to_vec().try_into().unwrap()).into();
+        let data_hash: u64 = NibbleNumber::new(data[22..38].to_vec().try_into().unwrap()).into();
+
+        let mut compressed_expanded: [u8; 16] = [0; 16];
+        compressed_expanded[15..16].clone_from_slice(&data[38..39]);
+
+        let compressed_expanded: u64 = NibbleNumber::new(compressed_expanded).into();
+        let compress_input =
+            CompressInput::try_from(compressed_expanded as u8).map_err(StegError::InvalidHeader)?;
+
+        let mut granularity_expanded: [u8; 16] = [0; 16];
+        granularity_expanded[15..16].clone_from_slice(&data[39..40]);
+
+        let granularity_expanded: u64 = NibbleNumber::new(granularity_expanded).into();
+        let granularity = ByteSplitGranularity::try_from(granularity_expanded as u8)
+            .map_err(StegError::InvalidHeader)?;
+
+        Ok(DataHeader {
+            magic,
+            version,
+            bytes_count,
+            data_hash,
+            compress_input,
+            granularity,
+            encryption: None,
+            digest: None,
+            error_correction: None,
+            embedding_mode: EmbeddingMode::Replacement,
+            channel_mask: ChannelMask::ALL,
+            transparent_pixels: false,
+            fragment: None,
+            share: None,
+            metadata: metadata::Metadata::default(),
+        })
+    }
+}
+
+impl From<DataHeader> for [u8; HEADER_LENGTH] {
+    fn from(header: DataHeader) -> [u8; HEADER_LENGTH] {
+        let magic = NibbleNumber::from(header.magic as u64);
+        let version = NibbleNumber::from(header.version as u64);
+        let bytes_count = NibbleNumber::from(header.bytes_count);
+        let hash = NibbleNumber::from(header.data_hash);
+
+        let compress_input: u8 = header.compress_input.into();
+        let compress_input = NibbleNumber::from(compress_input as u64);
+        let granularity: u8 = header.granularity.into();
+        let granularity = NibbleNumber::from(granularity as u64);
+
+        let mut raw: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+
+        raw[..4].clone_from_slice(&magic.data[12..16]);
+        raw[4..6].clone_from_slice(&version.data[14..16]);
+        raw[6..22].clone_from_slice(&bytes_count.data);
+        raw[22..38].clone_from_slice(&hash.data);
+        raw[38..39].clone_from_slice(&compress_input.data[15..16]);
+        raw[39..40].clone_from_slice(&granularity.data[15..16]);
+        raw
+    }
+}
+
+fn is_16_bit(color_type: image::ColorType) -> bool {
+    matches!(
+        color_type,
+        image::ColorType::L16
+            | image::ColorType::La16
+            | image::ColorType::Rgb16
+            | image::ColorType::Rgba16
+    )
+}
+
+#[derive(Debug)]
+struct NibbleNumber {
+    data: [u8; 16],
+}
+impl NibbleNumber {
+    fn new(data: [u8; 16]) -> Self {
+        Self { data }
+    }
+}
+
+impl From<usize> for NibbleNumber {
+    fn from(value: usize) -> Self {
+        NibbleNumber::from(value as u64)
+    }
+}
+
+impl From<u64> for NibbleNumber {
+    fn from(value: u64) -> Self {
+        let result: [u8; 16] = [
+            ((value & 0xF000000000000000) >> 60) as u8,
+            ((value & 0x0F00000000000000) >> 56) as u8,
+            ((value & 0x00F0000000000000) >> 52) as u8,
+            ((value & 0x000F000000000000) >> 48) as u8,
+            ((value & 0x0000F00000000000) >> 44) as u8,
+            ((value & 0x00000F0000000000) >> 40) as u8,
+            ((value & 0x000000F000000000) >> 36) as u8,
+            ((value & 0x0000000F00000000) >> 32) as u8,
+            ((value & 0x00000000F0000000) >> 28) as u8,
+            ((value & 0x000000000F000000) >> 24) as u8,
+            ((value & 0x0000000000F00000) >> 20) as u8,
+            ((value & 0x00000000000F0000) >> 16) as u8,
+            ((value & 0x000000000000F000) >> 12) as u8,
+            ((value & 0x0000000000000F00) >> 8) as u8,
+            ((value & 0x00000000000000F0) >> 4) as u8,
+            (value & 0x000000000000000F) as u8,
+        ];
+
+        NibbleNumber { data: result }
+    }
+}
+
+impl From<NibbleNumber> for u64 {
+    fn from(number: NibbleNumber) -> u64 {
+        let value_count: u64 = (number.data[0] as u64) << 60
+            | (number.data[1] as u64) << 56
+            | (number.data[2] as u64) << 52
+            | (number.data[3] as u64) << 48
+            | (number.data[4] as u64) << 44
+            | (number.data[5] as u64) << 40
+            | (number.data[6] as u64) << 36
+            | (number.data[7] as u64) << 32
+            | (number.data[8] as u64) << 28
+            | (number.data[9] as u64) << 24
+            | (number.data[10] as u64) << 20
+            | (number.data[11] as u64) << 16
+            | (number.data[12] as u64) << 12
+            | (number.data[13] as u64) << 8
+            | (number.data[14] as u64) << 4
+            | (number.data[15] as u64);
+
+        value_count
+    }
+}
+
+mod byte_encodings {
+    use super::ByteSplitGranularity;
+    use rand::Rng;
+
+    pub struct BytesZipper {}
+
+    impl BytesZipper {
+        // Merges src into the samples of dest found at each of the given positions
+        pub fn merge_into<S: Sample, I: Iterator<Item = usize>>(
+            dest: &mut [S],
+            positions: &mut I,
+            src: &[u8],
+            granularity: ByteSplitGranularity,
+        ) {
+            src.iter().zip(positions).for_each(|(right, position)| {
+                dest[position] = dest[position].zip(granularity, *right);
+            });
+        }
+
+        // Same as merge_into, moving each sample to the closest value that carries the data
+        // rather than overwriting its low bits
+        pub fn match_into<S: Sample, I: Iterator<Item = usize>, R: Rng>(
+            dest: &mut [S],
+            positions: &mut I,
+            src: &[u8],
+            granularity: ByteSplitGranularity,
+            rng: &mut R,
+        ) {
+            src.iter().zip(positions).for_each(|(right, position)| {
+                dest[position] = dest[position].matched(granularity, *right, rng.gen());
+            });
+        }
+    }
+
+    // A channel value of the cover image, the data is hidden in its low bits
+    pub trait Sample: Copy + Default + PartialEq {
+        fn zip(self, granularity: ByteSplitGranularity, right: u8) -> Self;
+        fn matched(self, granularity: ByteSplitGranularity, right: u8, round_up: bool) -> Self;
+        // The lowest 8 bits of the sample, which hold the embedded data
+        fn low_byte(self) -> u8;
+    }
+
+    impl Sample for u8 {
+        fn zip(self, granularity: ByteSplitGranularity, right: u8) -> Self {
+            zip_bytes(granularity, self, right)
+        }
+
+        fn matched(self, granularity: ByteSplitGranularity, right: u8, round_up: bool) -> Self {
+            match_bits(granularity, self as u32, u8::MAX as u32, right, round_up) as u8
+        }
+
+        fn low_byte(self) -> u8 {
+            self
+        }
+    }
+
+    impl Sample for u16 {
+        fn zip(self, granularity: ByteSplitGranularity, right: u8) -> Self {
+            (self & 0xFF00) | zip_bytes(granularity, self as u8, right) as u16
+        }
+
+        // The whole sample moves, so a change can carry into the high byte
+        fn matched(self, granularity: ByteSplitGranularity, right: u8, round_up: bool) -> Self {
+            match_bits(granularity, self as u32, u16::MAX as u32, right, round_up) as u16
+        }
+
+        fn low_byte(self) -> u8 {
+            self as u8
+        }
+    }
+
+    pub fn split_byte(granularity: ByteSplitGranularity, byte: u8) -> Vec<u8> {
+        split_bits(granularity, byte).collect()
+    }
+
+    // Same as split_byte without allocating, highest bits first
+    pub fn split_bits(granularity: ByteSplitGranularity, byte: u8) -> impl Iterator<Item = u8> {
+        let bits = granularity.bits();
+        let mask = (1 << bits) - 1;
+        (0..granularity.parts_per_byte() as u32)
+            .rev()
+            .map(move |i| (byte >> (i * bits)) & mask)
+    }
+
+    pub fn zip_bytes(granularity: ByteSplitGranularity, left: u8, right: u8) -> u8 {
+        match granularity {
+            ByteSplitGranularity::FourBits => (left & 0xF0) | (right & 0x0F),
+            ByteSplitGranularity::TwoBits => (left & 0xFC) | (right & 0x03),
+            ByteSplitGranularity::OneBit => (left & 0xFE) | (right & 0x01),
+        }
+    }
+
+    // Value closest to left, within 0..=max, whose low bits hold right. When the values below and
+    // above left are as close, round_up picks the one above. For OneBit this is LSB matching:
+    // left is kept if its LSB is right already, otherwise one is added or subtracted
+    pub fn match_bits(
+        granularity: ByteSplitGranularity,
+        left: u32,
+        max: u32,
+        right: u8,
+        round_up: bool,
+    ) -> u32 {
+        let step = 1 << granularity.bits();
+        let replaced = (left & !(step - 1)) | (right as u32 & (step - 1));
+        if replaced == left {
+            return left;
+        }
+
+        let below = if replaced < left {
+            Some(replaced)
+        } else {
+            replaced.checked_sub(step)
+        };
+        let above = if replaced > left {
+            Some(replaced)
+        } else {
+            Some(replaced + step).filter(|above| *above <= max)
+        };
+
+        match (below, above) {
+            (Some(below), Some(above)) => {
+                if above - left < left - below || (above - left == left - below && round_up) {
+                    above
+                } else {
+                    below
+                }
+            }
+            (Some(below), None) => below,
+            (None, Some(above)) => above,
+            (None, None) => replaced,
+        }
+    }
+
+    // FourBits = bottom 4 bits
+    // TwoBits = bottom 2 bits
+    pub fn merge_bytes(granularity: ByteSplitGranularity, bytes: &[u8]) -> u8 {
+        match granularity {
+            ByteSplitGranularity::FourBits => ((bytes[0] << 4) & 0xF0) | (bytes[1] & 0x0F),
+            ByteSplitGranularity::TwoBits => {
+                (bytes[0] << 6) & 0xC0
+                    | (bytes[1] << 4) & 0x30
+                    | (bytes[2] << 2) & 0x0C
+                    | (bytes[3] & 0x03)
+            }
+            ByteSplitGranularity::OneBit => {
+                (bytes[0] << 7) & 0x80
+                    | (bytes[1] << 6) & 0x40
+                    | (bytes[2] << 5) & 0x20
+                    | (bytes[3] << 4) & 0x10
+                    | (bytes[4] << 3) & 0x08
+                    | (bytes[5] << 2) & 0x04
+                    | (bytes[6] << 1) & 0x02
+                    | (bytes[7] & 0x01)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::ByteSplitGranularity;
+        use super::*;
+
+        #[test]
+        fn test_bytes_zipper_one_bit() {
+            let mut dest: Vec<u8> = vec![0xFE, 0xFE];
+            let src: Vec<u8> = vec![0x01, 0x01];
+
+            BytesZipper::merge_into(&mut dest, &mut (0..2), &src, ByteSplitGranularity::OneBit);
+            assert_eq!(vec![0xFF, 0xFF], dest);
+        }
+
+        #[test]
+        fn test_bytes_zipper_two_bits() {
+            let mut dest: Vec<u8> = vec![0xFC, 0xFC];
+            let src: Vec<u8> = vec![0x03, 0x03];
+
+            BytesZipper::merge_into(&mut dest, &mut (0..2), &src, ByteSplitGranularity::TwoBits);
+            assert_eq!(vec![0xFF, 0xFF], dest);
+        }
+
+        #[test]
+        fn test_bytes_zipper_four_bits() {
+            let mut dest: Vec<u8> = vec![0xFF, 0xFF];
+            let src: Vec<u8> = vec![0x07, 0x07];
+
+            BytesZipper::merge_into(&mut dest, &mut (0..2), &src, ByteSplitGranularity::FourBits);
+            assert_eq!(vec![0xF7, 0xF7], dest);
+        }
+
+        #[test]
+        fn test_bytes_zipper_sixteen_bit_samples() {
+            let mut dest: Vec<u16> = vec![0xABFF, 0x12FF];
+            let src: Vec<u8> = vec![0x07, 0x07];
+
+            BytesZipper::merge_into(&mut dest, &mut (0..2), &src, ByteSplitGranularity::FourBits);
+            assert_eq!(vec![0xABF7, 0x12F7], dest);
+            assert_eq!(0xF7, dest[0].low_byte());
+        }
+
+        #[test]
+        fn test_match_bits() {
+            let one_bit = |left, right, round_up| {
+                match_bits(ByteSplitGranularity::OneBit, left, 255, right, round_up)
+            };
+
+            assert_eq!(4, one_bit(4, 0, true));
+            assert_eq!(5, one_bit(4, 1, true));
+            assert_eq!(3, one_bit(4, 1, false));
+            assert_eq!(1, one_bit(0, 1, false));
+            assert_eq!(254, one_bit(255, 0, true));
+
+            // The closest value wins over the direction
+            let two_bits = |left, right, round_up| {
+                match_bits(ByteSplitGranularity::TwoBits, left, 255, right, round_up)
+            };
+            assert_eq!(3, two_bits(4, 3, true));
+            assert_eq!(9, two_bits(8, 1, false));
+            assert_eq!(251, two_bits(253, 3, false));
+            assert_eq!(255, two_bits(253, 3, true));
+            assert_eq!(252, two_bits(254, 0, true));
+
+            assert_eq!(
+                0x0100,
+                match_bits(ByteSplitGranularity::OneBit, 0x00FF, 0xFFFF, 0, true)
+            );
+        }
+
+        #[test]
+        fn test_bytes_matcher_one_bit() {
+            let mut dest: Vec<u8> = vec![0x00, 0xFF, 0x10, 0x10];
+            let src: Vec<u8> = vec![0x01, 0x00, 0x01, 0x00];
+            let mut rng = rand::thread_rng();
+
+            BytesZipper::match_into(
+                &mut dest,
+                &mut (0..4),
+                &src,
+                ByteSplitGranularity::OneBit,
+                &mut rng,
+            );
+            assert_eq!(0x01, dest[0]);
+            assert_eq!(0xFE, dest[1]);
+            assert!(dest[2] == 0x0F || dest[2] == 0x11);
+            assert_eq!(0x10, dest[3]);
+        }
+
+        #[test]
         fn test_split_merge_four_bits() {
             test_split_merge(0xFF, vec![0x0F, 0x0F], ByteSplitGranularity::FourBits);
             test_split_merge(0x0F, vec![0x0, 0x0F], ByteSplitGranularity::FourBits);
@@ -432,124 +1384,1320 @@ mod byte_encodings {
                 vec![0x01, 0x01, 0x01, 0x00, 0x01, 0x01, 0x00, 0x00],
                 ByteSplitGranularity::OneBit,
             );
-        }
+        }
+
+        #[test]
+        fn test_zip_four_bits() {
+            test_zip(0xF7, 0x3C, 0xFC, ByteSplitGranularity::FourBits);
+            test_zip(0x0, 0xAF, 0x0F, ByteSplitGranularity::FourBits);
+            test_zip(0x0, 0x0, 0x0, ByteSplitGranularity::FourBits);
+            test_zip(0xA9, 0x19, 0xA9, ByteSplitGranularity::FourBits);
+            test_zip(0xFF, 0xFF, 0xFF, ByteSplitGranularity::FourBits);
+        }
+
+        #[test]
+        fn test_zip_two_bits() {
+            test_zip(0xF7, 0x1, 0xF5, ByteSplitGranularity::TwoBits);
+            test_zip(0xF3, 0x3, 0xF3, ByteSplitGranularity::TwoBits);
+            test_zip(0x0, 0x3, 0x3, ByteSplitGranularity::TwoBits);
+            test_zip(0x3, 0x0, 0x0, ByteSplitGranularity::TwoBits);
+        }
+
+        #[test]
+        fn test_zip_one_bit() {
+            test_zip(0xF7, 0x1, 0xF7, ByteSplitGranularity::OneBit);
+            test_zip(0xF8, 0x1, 0xF9, ByteSplitGranularity::OneBit);
+            test_zip(0x0, 0x1, 0x1, ByteSplitGranularity::OneBit);
+            test_zip(0x1, 0x0, 0x0, ByteSplitGranularity::OneBit);
+        }
+
+        fn test_zip(left: u8, right: u8, expected: u8, granularity: ByteSplitGranularity) {
+            let result = zip_bytes(granularity, left, right);
+            assert_eq!(expected, result);
+        }
+
+        fn test_split_merge(input: u8, expected: Vec<u8>, granularity: ByteSplitGranularity) {
+            let result = split_byte(granularity, input);
+            assert_eq!(expected.len(), result.len());
+            assert_eq!(expected, result);
+
+            let merged = merge_bytes(granularity, &result);
+            assert_eq!(input, merged);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::byte_encodings::split_byte;
+    use super::decoder::*;
+    use super::encoder::*;
+    use super::error::StegError;
+    use super::metadata::Metadata;
+    use super::quality::QualityReport;
+    use super::{
+        ByteSplitGranularity, ChannelMask, CompressInput, DataHeader, DigestKind, EmbeddingMode,
+        ErrorCorrection, Fragment, Share, EXTENSIONS_LENGTH, HEADER_LENGTH, MAGIC, VERSION,
+    };
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+    use std::collections::hash_map::DefaultHasher;
+    use std::convert::TryFrom;
+    use std::hash::Hasher;
+    use std::io::{BufReader, Cursor, Read};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_encode_decode() {
+        // PNG image containing the encoded string "HELLO"
+        let image: [u8; 548] = [
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00,
+            0x00, 0x58, 0x1b, 0xb9, 0x08, 0x00, 0x00, 0x01, 0x83, 0x69, 0x43, 0x43, 0x50, 0x49,
+            0x43, 0x43, 0x20, 0x70, 0x72, 0x6f, 0x66, 0x69, 0x6c, 0x65, 0x00, 0x00, 0x28, 0x91,
+            0x7d, 0x91, 0x3d, 0x48, 0xc3, 0x40, 0x1c, 0xc5, 0x5f, 0x53, 0xa5, 0x45, 0x2a, 0x0e,
+            0x76, 0x10, 0x71, 0xc8, 0x50, 0x9d, 0x2c, 0x88, 0x4a, 0x71, 0xd4, 0x2a, 0x14, 0xa1,
+            0x42, 0xa8, 0x15, 0x5a, 0x75, 0x30, 0xb9, 0xf4, 0x0b, 0x9a, 0x34, 0x24, 0x29, 0x2e,
+            0x8e, 0x82, 0x6b, 0xc1, 0xc1, 0x8f, 0xc5, 0xaa, 0x83, 0x8b, 0xb3, 0xae, 0x0e, 0xae,
+            0x82, 0x20, 0xf8, 0x01, 0xe2, 0xe4, 0xe8, 0xa4, 0xe8, 0x22, 0x25, 0xfe, 0x2f, 0x29,
+            0xb4, 0x88, 0xf1, 0xe0, 0xb8, 0x1f, 0xef, 0xee, 0x3d, 0xee, 0xde, 0x01, 0x42, 0xb3,
+            0xca, 0x34, 0xab, 0x67, 0x02, 0xd0, 0x74, 0xdb, 0xcc, 0xa4, 0x92, 0x62, 0x2e, 0xbf,
+            0x2a, 0x86, 0x5e, 0x11, 0x41, 0x18, 0x51, 0x24, 0x10, 0x90, 0x99, 0x65, 0xcc, 0x49,
+            0x52, 0x1a, 0xbe, 0xe3, 0xeb, 0x1e, 0x01, 0xbe, 0xde, 0xc5, 0x79, 0x96, 0xff, 0xb9,
+            0x3f, 0x47, 0xbf, 0x5a, 0xb0, 0x18, 0x10, 0x10, 0x89, 0x67, 0x99, 0x61, 0xda, 0xc4,
+            0x1b, 0xc4, 0x89, 0x4d, 0xdb, 0xe0, 0xbc, 0x4f, 0x1c, 0x65, 0x65, 0x59, 0x25, 0x3e,
+            0x27, 0x1e, 0x37, 0xe9, 0x82, 0xc4, 0x8f, 0x5c, 0x57, 0x3c, 0x7e, 0xe3, 0x5c, 0x72,
+            0x59, 0xe0, 0x99, 0x51, 0x33, 0x9b, 0x99, 0x27, 0x8e, 0x12, 0x8b, 0xa5, 0x2e, 0x56,
+            0xba, 0x98, 0x95, 0x4d, 0x8d, 0x78, 0x9a, 0x38, 0xa6, 0x6a, 0x3a, 0xe5, 0x0b, 0x39,
+            0x8f, 0x55, 0xce, 0x5b, 0x9c, 0xb5, 0x6a, 0x9d, 0xb5, 0xef, 0xc9, 0x5f, 0x18, 0x29,
+            0xe8, 0x2b, 0xcb, 0x5c, 0xa7, 0x39, 0x82, 0x14, 0x16, 0xb1, 0x04, 0x09, 0x22, 0x14,
+            0xd4, 0x51, 0x41, 0x15, 0x36, 0xe2, 0xb4, 0xea, 0xa4, 0x58, 0xc8, 0xd0, 0x7e, 0xd2,
+            0xc7, 0x3f, 0xec, 0xfa, 0x25, 0x72, 0x29, 0xe4, 0xaa, 0x80, 0x91, 0x63, 0x01, 0x35,
+            0x68, 0x90, 0x5d, 0x3f, 0xf8, 0x1f, 0xfc, 0xee, 0xd6, 0x2a, 0x4e, 0x4d, 0x7a, 0x49,
+            0x91, 0x24, 0xd0, 0xfb, 0xe2, 0x38, 0x1f, 0xa3, 0x40, 0x68, 0x17, 0x68, 0x35, 0x1c,
+            0xe7, 0xfb, 0xd8, 0x71, 0x5a, 0x27, 0x40, 0xf0, 0x19, 0xb8, 0xd2, 0x3b, 0xfe, 0x5a,
+            0x13, 0x98, 0xf9, 0x24, 0xbd, 0xd1, 0xd1, 0x62, 0x47, 0xc0, 0xc0, 0x36, 0x70, 0x71,
+            0xdd, 0xd1, 0x94, 0x3d, 0xe0, 0x72, 0x07, 0x18, 0x7a, 0x32, 0x64, 0x53, 0x76, 0xa5,
+            0x20, 0x4d, 0xa1, 0x58, 0x04, 0xde, 0xcf, 0xe8, 0x9b, 0xf2, 0xc0, 0xe0, 0x2d, 0xd0,
+            0xb7, 0xe6, 0xf5, 0xd6, 0xde, 0xc7, 0xe9, 0x03, 0x90, 0xa5, 0xae, 0xd2, 0x37, 0xc0,
+            0xc1, 0x21, 0x30, 0x56, 0xa2, 0xec, 0x75, 0x9f, 0x77, 0x87, 0xbb, 0x7b, 0xfb, 0xf7,
+            0x4c, 0xbb, 0xbf, 0x1f, 0x57, 0xce, 0x72, 0x9c, 0xf7, 0xbf, 0xe8, 0x9e, 0x00, 0x00,
+            0x00, 0x09, 0x70, 0x48, 0x59, 0x73, 0x00, 0x00, 0x2e, 0x23, 0x00, 0x00, 0x2e, 0x23,
+            0x01, 0x78, 0xa5, 0x3f, 0x76, 0x00, 0x00, 0x00, 0x07, 0x74, 0x49, 0x4d, 0x45, 0x07,
+            0xe4, 0x0c, 0x08, 0x15, 0x07, 0x0c, 0x1d, 0x5f, 0x8d, 0xad, 0x00, 0x00, 0x00, 0x19,
+            0x74, 0x45, 0x58, 0x74, 0x43, 0x6f, 0x6d, 0x6d, 0x65, 0x6e, 0x74, 0x00, 0x43, 0x72,
+            0x65, 0x61, 0x74, 0x65, 0x64, 0x20, 0x77, 0x69, 0x74, 0x68, 0x20, 0x47, 0x49, 0x4d,
+            0x50, 0x57, 0x81, 0x0e, 0x17, 0x00, 0x00, 0x00, 0x0f, 0x49, 0x44, 0x41, 0x54, 0x08,
+            0xd7, 0x63, 0xfc, 0xff, 0xff, 0x3f, 0x03, 0x29, 0x00, 0x00, 0x8c, 0xd5, 0x02, 0xff,
+            0x2f, 0xcb, 0x21, 0xd3, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42,
+            0x60, 0x82,
+        ];
+
+        let mut cover = BufReader::new(Cursor::new(image.to_vec()));
+        let mut data = BufReader::new(Cursor::new("Hey!"));
+        let mut encode_output: Vec<u8> = Vec::new();
+
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits);
+
+        encoder
+            .encode(&mut cover, &mut data, &mut encode_output)
+            .expect("no error");
+
+        let mut decode_input = BufReader::new(Cursor::new(encode_output));
+        let mut decode_output: Vec<u8> = Vec::new();
+
+        let decoder = Decoder::new();
+
+        decoder
+            .decode(&mut decode_input, &mut decode_output)
+            .expect("no error");
+
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_with_ancillary_chunks() {
+        let cover = with_chunks(
+            cover_image(32, 32),
+            &[
+                (b"pHYs", &[0, 0, 0x2e, 0x23, 0, 0, 0x2e, 0x23, 1]),
+                (b"tIME", &[0x07, 0xe4, 12, 8, 21, 7, 12]),
+                (b"tEXt", b"Comment\0Created with GIMP"),
+            ],
+        );
+        let mut cover = BufReader::new(Cursor::new(cover));
+        let mut data = BufReader::new(Cursor::new("Hey!"));
+        let mut encode_output: Vec<u8> = Vec::new();
+
+        Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_ancillary_chunks()
+            .encode(&mut cover, &mut data, &mut encode_output)
+            .expect("no error");
+
+        let position = |chunk_type: &[u8]| {
+            encode_output
+                .windows(chunk_type.len())
+                .position(|w| w == chunk_type)
+                .unwrap()
+        };
+        assert!(position(b"IHDR") < position(b"pHYs"));
+        assert!(position(b"pHYs") < position(b"tIME"));
+        assert!(position(b"tIME") < position(b"tEXtComment"));
+        assert!(position(b"tEXtComment") < position(b"IDAT"));
+
+        let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_with_passphrase() {
+        let encoder = Encoder::new(CompressInput::Gzip, ByteSplitGranularity::OneBit)
+            .with_passphrase("correct horse battery staple");
+        let encode_output = encode(&encoder, "Hey!");
+
+        let decoder = Decoder::new().with_passphrase("correct horse battery staple");
+        let decode_output = decode(&decoder, &encode_output).expect("no error");
+
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+
+        let decoder = Decoder::new().with_passphrase("wrong passphrase");
+        let err = decode(&decoder, &encode_output).unwrap_err();
+        assert!(matches!(err, StegError::WrongPassphrase));
+        assert_eq!(
+            std::io::ErrorKind::PermissionDenied,
+            std::io::Error::from(err).kind()
+        );
+
+        let decoder = Decoder::new();
+        assert!(matches!(
+            decode(&decoder, &encode_output),
+            Err(StegError::PassphraseRequired)
+        ));
+    }
+
+    #[test]
+    fn test_decode_changed_header_with_passphrase() {
+        let encoder = Encoder::new(CompressInput::Zstd(5), ByteSplitGranularity::TwoBits)
+            .with_passphrase("secret");
+        let encode_output = encode(&encoder, "Hey!");
+
+        // Rewrites the header with another compression level, which does not change its length
+        // and decompresses all the same
+        let mut stego = image::load_from_memory(&encode_output).unwrap().to_rgba8();
+        let samples: &mut [u8] = &mut stego;
+        let (mut header, header_length) = DataHeader::read_from(samples).unwrap();
+        header.compress_input = CompressInput::Zstd(6);
+        let nibbles = header.to_nibbles().unwrap();
+        assert_eq!(header_length, nibbles.len());
+        for (sample, nibble) in samples.iter_mut().zip(nibbles) {
+            *sample = *sample & 0xF0 | nibble;
+        }
+
+        let changed = png_bytes(&image::DynamicImage::ImageRgba8(stego));
+        let err = decode(&Decoder::new().with_passphrase("secret"), &changed).unwrap_err();
+        assert!(matches!(err, StegError::WrongPassphrase));
+    }
+
+    #[test]
+    fn test_encode_decode_with_scatter_key() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::OneBit)
+            .with_scatter_key("scatter key");
+        let encode_output = encode(&encoder, "Hey!");
+
+        let decoder = Decoder::new().with_scatter_key("scatter key");
+        let decode_output = decode(&decoder, &encode_output).expect("no error");
+
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+
+        assert!(matches!(
+            decode(&Decoder::new(), &encode_output),
+            Err(StegError::InvalidMagic(_))
+        ));
+
+        let decoder = Decoder::new().with_scatter_key("other key");
+        assert!(matches!(
+            decode(&decoder, &encode_output),
+            Err(StegError::InvalidMagic(_))
+        ));
+
+        // the modified bytes should not be confined to the start of the image
+        let cover = image::load_from_memory(&cover_image(32, 32))
+            .unwrap()
+            .to_rgba8()
+            .into_raw();
+        let stego = image::load_from_memory(&encode_output)
+            .unwrap()
+            .to_rgba8()
+            .into_raw();
+        let last_changed = cover
+            .iter()
+            .zip(stego.iter())
+            .rposition(|(left, right)| left != right)
+            .unwrap();
+        assert!(last_changed > cover.len() / 2);
+    }
+
+    #[test]
+    fn test_decode_corrupted_data() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::FourBits);
+        let encode_output = encode(&encoder, "Hey!");
+
+        let header_length = DataHeader::new(CompressInput::None, ByteSplitGranularity::FourBits)
+            .to_nibbles()
+            .unwrap()
+            .len();
+        let mut stego = image::load_from_memory(&encode_output).unwrap().to_rgba8();
+        stego[(header_length as u32 / 4, 0)][0] ^= 0x01;
+
+        let corrupted = png_bytes(&image::DynamicImage::ImageRgba8(stego));
+
+        assert!(matches!(
+            decode(&Decoder::new(), &corrupted),
+            Err(StegError::HashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_corrected_data() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::FourBits)
+            .with_error_correction(0.1);
+        let encode_output = encode(&encoder, "Hey!");
+
+        let mut header = DataHeader::new(CompressInput::None, ByteSplitGranularity::FourBits);
+        header.error_correction = Some(ErrorCorrection::new(0.1));
+        let header_length = header.to_nibbles().unwrap().len();
+
+        // Every byte of data takes two samples, this corrupts three of them
+        let mut stego = image::load_from_memory(&encode_output).unwrap().to_rgba8();
+        let samples: &mut [u8] = &mut stego;
+        for i in [0, 4, 8].iter() {
+            samples[header_length + i] ^= 0x01;
+        }
+
+        let corrupted = png_bytes(&image::DynamicImage::ImageRgba8(stego));
+
+        let mut decode_output = Vec::new();
+        let report = Decoder::new()
+            .decode_with_report(&mut Cursor::new(corrupted), &mut decode_output)
+            .expect("no error");
+
+        assert_eq!(3, report.corrected_symbols);
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_with_digest() {
+        for digest_kind in [DigestKind::Crc32c, DigestKind::Blake3, DigestKind::Sha256].iter() {
+            let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+                .with_digest(*digest_kind);
+            let encode_output = encode(&encoder, "Hey!");
+
+            let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+
+            assert_eq!(
+                String::from("Hey!"),
+                String::from_utf8(decode_output).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_with_metadata() {
+        let metadata = Metadata::new()
+            .with_file_name("hey.txt")
+            .with_mime_type("text/plain")
+            .with_modified(UNIX_EPOCH + Duration::from_secs(1_607_000_000))
+            .with_tag("greeting")
+            .with_tag("short");
+
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        let mut data = BufReader::new(Cursor::new("Hey!"));
+        let mut encode_output: Vec<u8> = Vec::new();
+
+        Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .encode_with_metadata(&mut cover, &mut data, &metadata, &mut encode_output)
+            .expect("no error");
+
+        let mut decode_input = BufReader::new(Cursor::new(encode_output));
+        let mut decode_output: Vec<u8> = Vec::new();
+
+        let decoded_metadata = Decoder::new()
+            .decode_with_metadata(&mut decode_input, &mut decode_output)
+            .expect("no error");
+
+        assert_eq!(metadata, decoded_metadata);
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits);
+        let mut decode_input = BufReader::new(Cursor::new(encode(&encoder, "Hey!")));
+        let decoded_metadata = Decoder::new()
+            .decode_with_metadata(&mut decode_input, &mut Vec::new())
+            .expect("no error");
+        assert!(decoded_metadata.is_empty());
+
+        // With a passphrase the metadata is sealed along with the data, the header does not show it
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        let mut data = BufReader::new(Cursor::new("Hey!"));
+        let mut encode_output: Vec<u8> = Vec::new();
+        Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_passphrase("secret")
+            .encode_with_metadata(&mut cover, &mut data, &metadata, &mut encode_output)
+            .expect("no error");
+
+        let mut input = BufReader::new(Cursor::new(encode_output.clone()));
+        let header = Decoder::new().inspect(&mut input).unwrap().unwrap();
+        assert!(header.metadata().is_empty());
+
+        let mut decode_input = BufReader::new(Cursor::new(encode_output));
+        let mut decode_output: Vec<u8> = Vec::new();
+        let decoded_metadata = Decoder::new()
+            .with_passphrase("secret")
+            .decode_with_metadata(&mut decode_input, &mut decode_output)
+            .expect("no error");
+        assert_eq!(metadata, decoded_metadata);
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_read_out_of_range_modified() {
+        let mut header = DataHeader::new(CompressInput::None, ByteSplitGranularity::TwoBits);
+        header.metadata = Metadata::new().with_modified(UNIX_EPOCH + Duration::from_secs(1));
+        let mut nibbles = header.to_nibbles().unwrap();
+
+        // The only extension record: tag, length and the seconds, which are set to u64::MAX
+        let seconds = HEADER_LENGTH + EXTENSIONS_LENGTH + 6;
+        nibbles[seconds..seconds + 16].fill(0xF);
+
+        assert!(matches!(
+            DataHeader::read_from(&nibbles),
+            Err(StegError::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_inspect() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_digest(DigestKind::Sha256);
+        let mut input = BufReader::new(Cursor::new(encode(&encoder, "Hey!")));
+
+        let header = Decoder::new()
+            .inspect(&mut input)
+            .expect("no error")
+            .expect("header present");
+
+        assert_eq!(MAGIC, header.magic());
+        assert_eq!(VERSION, header.version());
+        assert_eq!(4, header.bytes_count());
+        assert_eq!(CompressInput::None, header.compress_input());
+        assert_eq!(ByteSplitGranularity::TwoBits, header.granularity());
+        assert_eq!(Some(DigestKind::Sha256), header.digest_kind());
+        assert!(!header.is_encrypted());
+
+        let mut input = BufReader::new(Cursor::new(cover_image(32, 32)));
+        assert!(Decoder::new()
+            .inspect(&mut input)
+            .expect("no error")
+            .is_none());
+    }
+
+    #[test]
+    fn test_encode_decode_compressed() {
+        let payload = "{\"level\":\"info\",\"msg\":\"request served\"}\n".repeat(20);
+
+        for (compress_input, recorded) in [
+            (CompressInput::Zstd(19), CompressInput::Zstd(19)),
+            (CompressInput::Zstd(50), CompressInput::Zstd(22)),
+            (CompressInput::Brotli(4), CompressInput::Brotli(4)),
+            (
+                CompressInput::Xz(CompressInput::XZ_DEFAULT_LEVEL),
+                CompressInput::Xz(CompressInput::XZ_DEFAULT_LEVEL),
+            ),
+        ]
+        .iter()
+        {
+            let encoder = Encoder::new(*compress_input, ByteSplitGranularity::OneBit);
+            let encode_output = encode(&encoder, &payload);
+
+            let decoder = Decoder::new();
+            assert_eq!(
+                payload.as_bytes().to_vec(),
+                decode(&decoder, &encode_output).expect("no error")
+            );
+            let header = decoder
+                .inspect(&mut Cursor::new(encode_output))
+                .unwrap()
+                .unwrap();
+            assert_eq!(*recorded, header.compress_input());
+            assert!(header.bytes_count() < payload.len() as u64 / 4);
+        }
+
+        // Auto records the codec it picked, and leaves data that does not compress as it is
+        let encoder = Encoder::new(CompressInput::Auto, ByteSplitGranularity::OneBit);
+        for (payload, compressed) in [(payload.as_str(), true), ("Hey!", false)].iter() {
+            let encode_output = encode(&encoder, payload);
+            let decoder = Decoder::new();
+            assert_eq!(
+                payload.as_bytes().to_vec(),
+                decode(&decoder, &encode_output).expect("no error")
+            );
 
-        #[test]
-        fn test_zip_four_bits() {
-            test_zip(0xF7, 0x3C, 0xFC, ByteSplitGranularity::FourBits);
-            test_zip(0x0, 0xAF, 0x0F, ByteSplitGranularity::FourBits);
-            test_zip(0x0, 0x0, 0x0, ByteSplitGranularity::FourBits);
-            test_zip(0xA9, 0x19, 0xA9, ByteSplitGranularity::FourBits);
-            test_zip(0xFF, 0xFF, 0xFF, ByteSplitGranularity::FourBits);
+            let header = decoder
+                .inspect(&mut Cursor::new(encode_output))
+                .unwrap()
+                .unwrap();
+            assert_ne!(CompressInput::Auto, header.compress_input());
+            assert_eq!(*compressed, header.compress_input() != CompressInput::None);
         }
 
-        #[test]
-        fn test_zip_two_bits() {
-            test_zip(0xF7, 0x1, 0xF5, ByteSplitGranularity::TwoBits);
-            test_zip(0xF3, 0x3, 0xF3, ByteSplitGranularity::TwoBits);
-            test_zip(0x0, 0x3, 0x3, ByteSplitGranularity::TwoBits);
-            test_zip(0x3, 0x0, 0x0, ByteSplitGranularity::TwoBits);
-        }
+        assert!(CompressInput::try_from(u8::from(CompressInput::Auto)).is_err());
+    }
 
-        #[test]
-        fn test_zip_one_bit() {
-            test_zip(0xF7, 0x1, 0xF7, ByteSplitGranularity::OneBit);
-            test_zip(0xF8, 0x1, 0xF9, ByteSplitGranularity::OneBit);
-            test_zip(0x0, 0x1, 0x1, ByteSplitGranularity::OneBit);
-            test_zip(0x1, 0x0, 0x0, ByteSplitGranularity::OneBit);
+    #[test]
+    fn test_capacity() {
+        let mut rng = ChaCha20Rng::from_seed([3; 32]);
+        let metadata = Metadata::new().with_file_name("random.bin");
+
+        for compress_input in [
+            CompressInput::None,
+            CompressInput::Gzip,
+            CompressInput::Zstd(1),
+            CompressInput::Brotli(CompressInput::BROTLI_DEFAULT_LEVEL),
+            CompressInput::Xz(0),
+            CompressInput::Auto,
+        ]
+        .iter()
+        {
+            for granularity in [
+                ByteSplitGranularity::OneBit,
+                ByteSplitGranularity::TwoBits,
+                ByteSplitGranularity::FourBits,
+            ]
+            .iter()
+            {
+                let encoder = Encoder::new(*compress_input, *granularity);
+                let capacity = encoder
+                    .cover_capacity(Cursor::new(cover_image(32, 32)), &metadata)
+                    .expect("no error");
+                assert_eq!(
+                    capacity,
+                    encoder
+                        .capacity(32, 32, image::ColorType::Rgba8, &metadata)
+                        .expect("no error")
+                );
+
+                // random data does not compress, so this is the worst case
+                let mut data = vec![0; capacity as usize];
+                rng.fill_bytes(&mut data);
+
+                let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+                encoder
+                    .encode_with_metadata(&mut cover, &mut &data[..], &metadata, &mut Vec::new())
+                    .expect("no error");
+
+                if *compress_input == CompressInput::None {
+                    data.push(0);
+                    let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+                    assert!(matches!(
+                        encoder.encode_with_metadata(
+                            &mut cover,
+                            &mut &data[..],
+                            &metadata,
+                            &mut Vec::new()
+                        ),
+                        Err(StegError::CoverTooSmall { .. })
+                    ));
+                }
+            }
         }
+    }
 
-        fn test_zip(left: u8, right: u8, expected: u8, granularity: ByteSplitGranularity) {
-            let result = zip_bytes(granularity, left, right);
-            assert_eq!(expected, result);
-        }
+    #[test]
+    fn test_capacity_with_passphrase() {
+        let metadata = Metadata::new()
+            .with_file_name("random.bin")
+            .with_tag("secret");
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_passphrase("secret");
+        let capacity = encoder
+            .cover_capacity(Cursor::new(cover_image(32, 32)), &metadata)
+            .expect("no error");
 
-        fn test_split_merge(input: u8, expected: Vec<u8>, granularity: ByteSplitGranularity) {
-            let result = split_byte(granularity, input);
-            assert_eq!(expected.len(), result.len());
-            assert_eq!(expected, result);
+        let mut data = vec![0; capacity as usize];
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        encoder
+            .encode_with_metadata(&mut cover, &mut &data[..], &metadata, &mut Vec::new())
+            .expect("no error");
 
-            let merged = merge_bytes(granularity, &result);
-            assert_eq!(input, merged);
+        data.push(0);
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        assert!(matches!(
+            encoder.encode_with_metadata(&mut cover, &mut &data[..], &metadata, &mut Vec::new()),
+            Err(StegError::CoverTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn test_capacity_with_error_correction() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_passphrase("passphrase")
+            .with_error_correction(0.2);
+        let capacity = encoder
+            .cover_capacity(Cursor::new(cover_image(32, 32)), &Metadata::new())
+            .expect("no error");
+
+        let data = vec![0x42; capacity as usize];
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        encoder
+            .encode(&mut cover, &mut &data[..], &mut Vec::new())
+            .expect("no error");
+
+        let data = vec![0x42; capacity as usize + 1];
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        assert!(matches!(
+            encoder.encode(&mut cover, &mut &data[..], &mut Vec::new()),
+            Err(StegError::CoverTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn test_auto_granularity() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::FourBits)
+            .with_auto_granularity();
+
+        for (data_size, expected) in [
+            (10, ByteSplitGranularity::OneBit),
+            (1000, ByteSplitGranularity::TwoBits),
+            (2000, ByteSplitGranularity::FourBits),
+        ]
+        .iter()
+        {
+            let data = "x".repeat(*data_size);
+            let encode_output = encode(&encoder, &data);
+
+            let mut input = BufReader::new(Cursor::new(encode_output.clone()));
+            let header = Decoder::new().inspect(&mut input).unwrap().unwrap();
+            assert_eq!(*expected, header.granularity());
+
+            let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+            assert_eq!(data, String::from_utf8(decode_output).unwrap());
         }
+
+        // the granularity given to the encoder is the most invasive one allowed
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_auto_granularity();
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        let mut data = BufReader::new(Cursor::new("x".repeat(2000)));
+        assert!(matches!(
+            encoder.encode(&mut cover, &mut data, &mut Vec::new()),
+            Err(StegError::CoverTooSmall { .. })
+        ));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::decoder::*;
-    use super::encoder::*;
-    use super::{ByteSplitGranularity, CompressInput};
-    use std::io::{BufReader, Cursor};
+    #[test]
+    fn test_encode_decode_16_bit() {
+        let cover = image::ImageBuffer::from_fn(32, 32, |x, y| {
+            image::Rgba([(x * 2000) as u16, (y * 1500) as u16, 0x8000, 0xFFFF])
+        });
+        let cover_png = png_bytes(&image::DynamicImage::ImageRgba16(cover.clone()));
+
+        let mut encode_output: Vec<u8> = Vec::new();
+        Encoder::new(CompressInput::None, ByteSplitGranularity::FourBits)
+            .encode(
+                &mut BufReader::new(Cursor::new(cover_png.clone())),
+                &mut BufReader::new(Cursor::new("Hey!")),
+                &mut encode_output,
+            )
+            .expect("no error");
+
+        let stego = image::load_from_memory(&encode_output).unwrap();
+        assert_eq!(image::ColorType::Rgba16, stego.color());
+
+        // only the low bits of each sample are touched
+        let stego = stego.to_rgba16();
+        assert_ne!(cover, stego);
+        assert!(cover
+            .iter()
+            .zip(stego.iter())
+            .all(|(left, right)| left & 0xFFF0 == right & 0xFFF0));
+
+        let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+    }
 
     #[test]
-    fn test_encode_decode() {
-        // PNG image containing the encoded string "HELLO"
-        let image: [u8; 548] = [
-            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48,
-            0x44, 0x52, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00,
-            0x00, 0x58, 0x1b, 0xb9, 0x08, 0x00, 0x00, 0x01, 0x83, 0x69, 0x43, 0x43, 0x50, 0x49,
-            0x43, 0x43, 0x20, 0x70, 0x72, 0x6f, 0x66, 0x69, 0x6c, 0x65, 0x00, 0x00, 0x28, 0x91,
-            0x7d, 0x91, 0x3d, 0x48, 0xc3, 0x40, 0x1c, 0xc5, 0x5f, 0x53, 0xa5, 0x45, 0x2a, 0x0e,
-            0x76, 0x10, 0x71, 0xc8, 0x50, 0x9d, 0x2c, 0x88, 0x4a, 0x71, 0xd4, 0x2a, 0x14, 0xa1,
-            0x42, 0xa8, 0x15, 0x5a, 0x75, 0x30, 0xb9, 0xf4, 0x0b, 0x9a, 0x34, 0x24, 0x29, 0x2e,
-            0x8e, 0x82, 0x6b, 0xc1, 0xc1, 0x8f, 0xc5, 0xaa, 0x83, 0x8b, 0xb3, 0xae, 0x0e, 0xae,
-            0x82, 0x20, 0xf8, 0x01, 0xe2, 0xe4, 0xe8, 0xa4, 0xe8, 0x22, 0x25, 0xfe, 0x2f, 0x29,
-            0xb4, 0x88, 0xf1, 0xe0, 0xb8, 0x1f, 0xef, 0xee, 0x3d, 0xee, 0xde, 0x01, 0x42, 0xb3,
-            0xca, 0x34, 0xab, 0x67, 0x02, 0xd0, 0x74, 0xdb, 0xcc, 0xa4, 0x92, 0x62, 0x2e, 0xbf,
-            0x2a, 0x86, 0x5e, 0x11, 0x41, 0x18, 0x51, 0x24, 0x10, 0x90, 0x99, 0x65, 0xcc, 0x49,
-            0x52, 0x1a, 0xbe, 0xe3, 0xeb, 0x1e, 0x01, 0xbe, 0xde, 0xc5, 0x79, 0x96, 0xff, 0xb9,
-            0x3f, 0x47, 0xbf, 0x5a, 0xb0, 0x18, 0x10, 0x10, 0x89, 0x67, 0x99, 0x61, 0xda, 0xc4,
-            0x1b, 0xc4, 0x89, 0x4d, 0xdb, 0xe0, 0xbc, 0x4f, 0x1c, 0x65, 0x65, 0x59, 0x25, 0x3e,
-            0x27, 0x1e, 0x37, 0xe9, 0x82, 0xc4, 0x8f, 0x5c, 0x57, 0x3c, 0x7e, 0xe3, 0x5c, 0x72,
-            0x59, 0xe0, 0x99, 0x51, 0x33, 0x9b, 0x99, 0x27, 0x8e, 0x12, 0x8b, 0xa5, 0x2e, 0x56,
-            0xba, 0x98, 0x95, 0x4d, 0x8d, 0x78, 0x9a, 0x38, 0xa6, 0x6a, 0x3a, 0xe5, 0x0b, 0x39,
-            0x8f, 0x55, 0xce, 0x5b, 0x9c, 0xb5, 0x6a, 0x9d, 0xb5, 0xef, 0xc9, 0x5f, 0x18, 0x29,
-            0xe8, 0x2b, 0xcb, 0x5c, 0xa7, 0x39, 0x82, 0x14, 0x16, 0xb1, 0x04, 0x09, 0x22, 0x14,
-            0xd4, 0x51, 0x41, 0x15, 0x36, 0xe2, 0xb4, 0xea, 0xa4, 0x58, 0xc8, 0xd0, 0x7e, 0xd2,
-            0xc7, 0x3f, 0xec, 0xfa, 0x25, 0x72, 0x29, 0xe4, 0xaa, 0x80, 0x91, 0x63, 0x01, 0x35,
-            0x68, 0x90, 0x5d, 0x3f, 0xf8, 0x1f, 0xfc, 0xee, 0xd6, 0x2a, 0x4e, 0x4d, 0x7a, 0x49,
-            0x91, 0x24, 0xd0, 0xfb, 0xe2, 0x38, 0x1f, 0xa3, 0x40, 0x68, 0x17, 0x68, 0x35, 0x1c,
-            0xe7, 0xfb, 0xd8, 0x71, 0x5a, 0x27, 0x40, 0xf0, 0x19, 0xb8, 0xd2, 0x3b, 0xfe, 0x5a,
-            0x13, 0x98, 0xf9, 0x24, 0xbd, 0xd1, 0xd1, 0x62, 0x47, 0xc0, 0xc0, 0x36, 0x70, 0x71,
-            0xdd, 0xd1, 0x94, 0x3d, 0xe0, 0x72, 0x07, 0x18, 0x7a, 0x32, 0x64, 0x53, 0x76, 0xa5,
-            0x20, 0x4d, 0xa1, 0x58, 0x04, 0xde, 0xcf, 0xe8, 0x9b, 0xf2, 0xc0, 0xe0, 0x2d, 0xd0,
-            0xb7, 0xe6, 0xf5, 0xd6, 0xde, 0xc7, 0xe9, 0x03, 0x90, 0xa5, 0xae, 0xd2, 0x37, 0xc0,
-            0xc1, 0x21, 0x30, 0x56, 0xa2, 0xec, 0x75, 0x9f, 0x77, 0x87, 0xbb, 0x7b, 0xfb, 0xf7,
-            0x4c, 0xbb, 0xbf, 0x1f, 0x57, 0xce, 0x72, 0x9c, 0xf7, 0xbf, 0xe8, 0x9e, 0x00, 0x00,
-            0x00, 0x09, 0x70, 0x48, 0x59, 0x73, 0x00, 0x00, 0x2e, 0x23, 0x00, 0x00, 0x2e, 0x23,
-            0x01, 0x78, 0xa5, 0x3f, 0x76, 0x00, 0x00, 0x00, 0x07, 0x74, 0x49, 0x4d, 0x45, 0x07,
-            0xe4, 0x0c, 0x08, 0x15, 0x07, 0x0c, 0x1d, 0x5f, 0x8d, 0xad, 0x00, 0x00, 0x00, 0x19,
-            0x74, 0x45, 0x58, 0x74, 0x43, 0x6f, 0x6d, 0x6d, 0x65, 0x6e, 0x74, 0x00, 0x43, 0x72,
-            0x65, 0x61, 0x74, 0x65, 0x64, 0x20, 0x77, 0x69, 0x74, 0x68, 0x20, 0x47, 0x49, 0x4d,
-            0x50, 0x57, 0x81, 0x0e, 0x17, 0x00, 0x00, 0x00, 0x0f, 0x49, 0x44, 0x41, 0x54, 0x08,
-            0xd7, 0x63, 0xfc, 0xff, 0xff, 0x3f, 0x03, 0x29, 0x00, 0x00, 0x8c, 0xd5, 0x02, 0xff,
-            0x2f, 0xcb, 0x21, 0xd3, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42,
-            0x60, 0x82,
+    fn test_encode_decode_lsb_matching() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::OneBit)
+            .with_embedding_mode(EmbeddingMode::Matching);
+        let data = "Hey!".repeat(20);
+        let encode_output = encode(&encoder, &data);
+
+        let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+        assert_eq!(data, String::from_utf8(decode_output).unwrap());
+
+        let mut input = BufReader::new(Cursor::new(encode_output.clone()));
+        let header = Decoder::new().inspect(&mut input).unwrap().unwrap();
+        assert_eq!(EmbeddingMode::Matching, header.embedding_mode());
+
+        // Data samples move by one at most, and unlike replacement some of them leave their pair
+        // of values (2k, 2k + 1)
+        let header_length = header.to_nibbles().unwrap().len();
+        let cover = image::load_from_memory(&cover_image(32, 32))
+            .unwrap()
+            .to_rgba8();
+        let stego = image::load_from_memory(&encode_output).unwrap().to_rgba8();
+        let samples = || cover.iter().zip(stego.iter()).skip(header_length);
+        assert!(samples().all(|(c, s)| (*c as i16 - *s as i16).abs() <= 1));
+        assert!(samples().any(|(c, s)| c / 2 != s / 2));
+    }
+
+    #[test]
+    fn test_encode_with_report() {
+        let cover = cover_image(32, 32);
+        let mut encode_output: Vec<u8> = Vec::new();
+        let report = Encoder::new(CompressInput::None, ByteSplitGranularity::OneBit)
+            .encode_with_report(
+                &mut BufReader::new(Cursor::new(cover.clone())),
+                &mut BufReader::new(Cursor::new("Hey!".repeat(20))),
+                &Metadata::default(),
+                &mut encode_output,
+            )
+            .expect("no error");
+
+        assert!(report.changed_samples > 0);
+        assert!(report.max_delta <= 15);
+        assert!(report.psnr > 40.0);
+        assert!(report.ssim > 0.95);
+
+        let read = QualityReport::read(&mut Cursor::new(cover), &mut Cursor::new(encode_output))
+            .expect("no error");
+        assert_eq!(report, read);
+    }
+
+    #[test]
+    fn test_encode_min_psnr() {
+        let encode = |min_psnr| {
+            let mut encode_output: Vec<u8> = Vec::new();
+            Encoder::new(CompressInput::None, ByteSplitGranularity::FourBits)
+                .with_min_psnr(min_psnr)
+                .encode(
+                    &mut BufReader::new(Cursor::new(cover_image(32, 32))),
+                    &mut BufReader::new(Cursor::new("Hey!".repeat(100))),
+                    &mut encode_output,
+                )
+                .map(|_| encode_output)
+        };
+
+        assert!(!encode(20.0).expect("no error").is_empty());
+        assert!(matches!(
+            encode(60.0),
+            Err(StegError::QualityTooLow { min_psnr, .. }) if min_psnr == 60.0
+        ));
+    }
+
+    #[test]
+    fn test_encode_decode_keeps_colour_type() {
+        let rgba = image::load_from_memory(&cover_image(32, 32)).unwrap();
+        let covers = [
+            image::DynamicImage::ImageLuma8(rgba.to_luma8()),
+            image::DynamicImage::ImageLumaA8(rgba.to_luma_alpha8()),
+            image::DynamicImage::ImageRgb8(rgba.to_rgb8()),
+            rgba,
         ];
 
-        let mut cover = BufReader::new(Cursor::new(image.to_vec()));
+        for cover in covers.iter() {
+            let cover_png = png_bytes(cover);
+
+            let mut encode_output: Vec<u8> = Vec::new();
+            Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+                .with_native_colour_type()
+                .encode(
+                    &mut BufReader::new(Cursor::new(cover_png)),
+                    &mut BufReader::new(Cursor::new("Hey!")),
+                    &mut encode_output,
+                )
+                .expect("no error");
+
+            let stego = image::load_from_memory(&encode_output).unwrap();
+            assert_eq!(cover.color(), stego.color());
+
+            let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+            assert_eq!(
+                String::from("Hey!"),
+                String::from_utf8(decode_output).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_output_formats() {
+        for format in [
+            image::ImageFormat::Png,
+            image::ImageFormat::Bmp,
+            image::ImageFormat::Tiff,
+            image::ImageFormat::Tga,
+            image::ImageFormat::Pnm,
+            image::ImageFormat::Qoi,
+            image::ImageFormat::WebP,
+        ]
+        .iter()
+        {
+            let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+                .with_output_format(*format);
+            let encode_output = encode(&encoder, "Hey!");
+
+            // TGA images can not be told apart from garbage, they are only read when asked to
+            let decoder = if *format == image::ImageFormat::Tga {
+                assert!(matches!(
+                    decode(&Decoder::new(), &encode_output),
+                    Err(StegError::ImageFormat(image::ImageError::Unsupported(_)))
+                ));
+                Decoder::new().with_input_format(*format)
+            } else {
+                assert_eq!(*format, image::guess_format(&encode_output).unwrap());
+                Decoder::new()
+            };
+
+            let decode_output = decode(&decoder, &encode_output).expect("no error");
+            assert_eq!(
+                String::from("Hey!"),
+                String::from_utf8(decode_output).unwrap(),
+            );
+        }
+
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_output_format(image::ImageFormat::Jpeg);
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
         let mut data = BufReader::new(Cursor::new("Hey!"));
+        assert!(matches!(
+            encoder.encode(&mut cover, &mut data, &mut Vec::new()),
+            Err(StegError::LossyFormat(image::ImageFormat::Jpeg))
+        ));
+    }
+
+    #[test]
+    fn test_encode_decode_native_grayscale_output_formats() {
+        let rgba = image::load_from_memory(&cover_image(32, 32)).unwrap();
+        let cover_png = png_bytes(&image::DynamicImage::ImageLuma16(rgba.to_luma16()));
+
+        for format in [
+            image::ImageFormat::Bmp,
+            image::ImageFormat::Tiff,
+            image::ImageFormat::Tga,
+            image::ImageFormat::Pnm,
+            image::ImageFormat::Qoi,
+            image::ImageFormat::WebP,
+        ]
+        .iter()
+        {
+            let mut encode_output: Vec<u8> = Vec::new();
+            Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+                .with_native_colour_type()
+                .with_output_format(*format)
+                .encode(
+                    &mut Cursor::new(cover_png.clone()),
+                    &mut BufReader::new(Cursor::new("Hey!")),
+                    &mut encode_output,
+                )
+                .expect("no error");
+
+            let decoder = Decoder::new().with_input_format(*format);
+            let decode_output = decode(&decoder, &encode_output).expect("no error");
+            assert_eq!(
+                String::from("Hey!"),
+                String::from_utf8(decode_output).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_bmp_cover() {
+        let cover = image::load_from_memory(&cover_image(32, 32)).unwrap();
+        let mut cover_bmp = Cursor::new(Vec::new());
+        cover
+            .write_to(&mut cover_bmp, image::ImageFormat::Bmp)
+            .expect("no error");
+        cover_bmp.set_position(0);
+
         let mut encode_output: Vec<u8> = Vec::new();
+        Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .encode(
+                &mut cover_bmp,
+                &mut BufReader::new(Cursor::new("Hey!")),
+                &mut encode_output,
+            )
+            .expect("no error");
 
-        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits);
+        let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+        assert_eq!(
+            String::from("Hey!"),
+            String::from_utf8(decode_output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_channel_mask() {
+        let cover = image::load_from_memory(&cover_image(32, 32))
+            .unwrap()
+            .to_rgba8();
+
+        for (mask, changed) in [
+            (ChannelMask::COLOUR, [true, true, true, false]),
+            (ChannelMask::BLUE, [false, false, true, false]),
+            (
+                ChannelMask::RED | ChannelMask::ALPHA,
+                [true, false, false, true],
+            ),
+        ] {
+            let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+                .with_channel_mask(mask);
+            let encode_output = encode(&encoder, "Hey!");
+
+            let decoder = Decoder::new();
+            let decode_output = decode(&decoder, &encode_output).expect("no error");
+            assert_eq!("Hey!", String::from_utf8(decode_output).unwrap());
+
+            let mut input = BufReader::new(Cursor::new(encode_output.clone()));
+            let header = decoder.inspect(&mut input).unwrap().unwrap();
+            assert_eq!(mask, header.channel_mask());
+
+            // The header goes into the masked channels along with the data, no other one changes
+            let stego = image::load_from_memory(&encode_output).unwrap().to_rgba8();
+            for (channel, changed) in changed.iter().enumerate() {
+                let differs = cover
+                    .pixels()
+                    .zip(stego.pixels())
+                    .any(|(c, s)| c.0[channel] != s.0[channel]);
+                assert_eq!(*changed, differs, "{:?} channel {}", mask, channel);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_channel_mask_scattered() {
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_channel_mask(ChannelMask::BLUE | ChannelMask::ALPHA)
+            .with_scatter_key("scatter key");
+        let encode_output = encode(&encoder, &"Hey!".repeat(20));
 
+        let decoder = Decoder::new().with_scatter_key("scatter key");
+        let decode_output = decode(&decoder, &encode_output).expect("no error");
+        assert_eq!("Hey!".repeat(20), String::from_utf8(decode_output).unwrap());
+
+        assert!(decode(&Decoder::new(), &encode_output).is_err());
+    }
+
+    #[test]
+    fn test_encode_decode_transparent_pixels() {
+        // The right half of the cover image is transparent
+        let mut cover = image::load_from_memory(&cover_image(32, 32))
+            .unwrap()
+            .to_rgba8();
+        cover
+            .enumerate_pixels_mut()
+            .filter(|(x, _, _)| *x >= 16)
+            .for_each(|(_, _, pixel)| pixel.0[3] = 0);
+        let cover_png = png_bytes(&image::DynamicImage::ImageRgba8(cover.clone()));
+
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_transparent_pixels();
+        assert_eq!(
+            (16 * 32 * 3 - 44 - 5 * 2) / 4,
+            encoder
+                .cover_capacity(Cursor::new(cover_png.clone()), &Metadata::new())
+                .expect("no error")
+        );
+
+        // The dimensions alone do not tell how many pixels are transparent
+        assert!(matches!(
+            encoder.capacity(32, 32, image::ColorType::Rgba8, &Metadata::new()),
+            Err(StegError::CoverRequired)
+        ));
+
+        let mut encode_output: Vec<u8> = Vec::new();
         encoder
-            .encode(&mut cover, &mut data, &mut encode_output)
+            .encode(
+                &mut Cursor::new(cover_png),
+                &mut BufReader::new(Cursor::new("Hey!".repeat(50))),
+                &mut encode_output,
+            )
             .expect("no error");
 
-        let mut decode_input = BufReader::new(Cursor::new(encode_output));
-        let mut decode_output: Vec<u8> = Vec::new();
+        let decode_output = decode(&Decoder::new(), &encode_output).expect("no error");
+        assert_eq!("Hey!".repeat(50), String::from_utf8(decode_output).unwrap());
+
+        // Only the colour channels of transparent pixels change, the header included
+        let stego = image::load_from_memory(&encode_output).unwrap().to_rgba8();
+        for (c, s) in cover.pixels().zip(stego.pixels()) {
+            assert_eq!(c.0[3], s.0[3]);
+            if c.0[3] != 0 {
+                assert_eq!(c, s);
+            }
+        }
+        assert_ne!(cover, stego);
+
+        // Opaque covers have no room at all
+        let err = Encoder::new(CompressInput::None, ByteSplitGranularity::TwoBits)
+            .with_transparent_pixels()
+            .encode(
+                &mut Cursor::new(cover_image(32, 32)),
+                &mut BufReader::new(Cursor::new("Hey!")),
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+        assert!(matches!(err, StegError::CoverTooSmall { available: 0, .. }));
+    }
+
+    #[test]
+    fn test_encode_decode_image() {
+        let cover = image::DynamicImage::ImageRgb8(
+            image::load_from_memory(&cover_image(32, 32))
+                .unwrap()
+                .to_rgb8(),
+        );
+
+        let encoder = Encoder::new(CompressInput::Gzip, ByteSplitGranularity::TwoBits);
+        let stego = encoder
+            .encode_image(&cover, &mut Cursor::new("Hey!"))
+            .expect("no error");
+        assert_eq!(image::ColorType::Rgba8, stego.color());
 
         let decoder = Decoder::new();
+        assert_eq!(
+            b"Hey!".to_vec(),
+            decoder.decode_image(&stego).expect("no error")
+        );
+        let header = decoder.inspect_image(&stego).unwrap().unwrap();
+        assert_eq!(CompressInput::Gzip, header.compress_input());
+        assert!(decoder.inspect_image(&cover).unwrap().is_none());
 
-        decoder
-            .decode(&mut decode_input, &mut decode_output)
+        // The file based API reads the same image once it is written
+        assert_eq!(
+            b"Hey!".to_vec(),
+            decode(&decoder, &png_bytes(&stego)).expect("no error")
+        );
+
+        let stego = encoder
+            .with_native_colour_type()
+            .encode_image(&cover, &mut Cursor::new("Hey!"))
+            .expect("no error");
+        assert_eq!(image::ColorType::Rgb8, stego.color());
+        assert_eq!(
+            b"Hey!".to_vec(),
+            decoder.decode_image(&stego).expect("no error")
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_images() {
+        let covers: Vec<image::DynamicImage> = [(32, 32), (48, 32), (16, 16)]
+            .iter()
+            .map(|(w, h)| image::load_from_memory(&cover_image(*w, *h)).unwrap())
+            .collect();
+        let payload: Vec<u8> = (0..1000).map(|i| (i * 31 % 251) as u8).collect();
+
+        let encoder = Encoder::new(CompressInput::None, ByteSplitGranularity::OneBit)
+            .with_error_correction(0.1);
+        let metadata = Metadata::new().with_file_name("payload.bin");
+        let images = encoder
+            .encode_images(&covers, &mut Cursor::new(&payload), &metadata)
+            .expect("no error");
+        assert_eq!(3, images.len());
+
+        // Too much for any of the covers on its own
+        assert!(matches!(
+            encoder.encode_image(&covers[1], &mut Cursor::new(&payload)),
+            Err(StegError::CoverTooSmall { .. })
+        ));
+
+        let decoder = Decoder::new();
+        let fragments: Vec<Fragment> = images
+            .iter()
+            .map(|img| decoder.inspect_image(img).unwrap().unwrap())
+            .map(|header| header.fragment().expect("fragment"))
+            .collect();
+        assert_eq!(
+            vec![0, 1, 2],
+            fragments.iter().map(|f| f.index()).collect::<Vec<usize>>()
+        );
+        assert!(fragments.iter().all(|f| f.count() == 3));
+        assert!(fragments
+            .iter()
+            .all(|f| f.message_id() == fragments[0].message_id()));
+
+        let shuffled = vec![images[2].clone(), images[0].clone(), images[1].clone()];
+        let (decoded, report) = decoder
+            .decode_images_with_report(&shuffled)
+            .expect("no error");
+        assert_eq!(payload, decoded);
+        assert_eq!(Some("payload.bin"), report.metadata.file_name.as_deref());
+
+        // Duplicates are only used once
+        let mut duplicated = shuffled.clone();
+        duplicated.push(images[0].clone());
+        assert_eq!(
+            payload,
+            decoder.decode_images(&duplicated).expect("no error")
+        );
+
+        match decoder.decode_images(&[images[1].clone()]) {
+            Err(StegError::MissingFragments { missing, count }) => {
+                assert_eq!(vec![0, 2], missing);
+                assert_eq!(3, count);
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert!(matches!(
+            decoder.decode_image(&images[2]),
+            Err(StegError::MissingFragments { .. })
+        ));
+
+        // Images of another payload can not be mixed in
+        let other = encoder
+            .encode_images(&covers, &mut Cursor::new(&payload), &metadata)
+            .expect("no error");
+        assert!(matches!(
+            decoder.decode_images(&[images[0].clone(), images[1].clone(), other[2].clone()]),
+            Err(StegError::MixedFragments)
+        ));
+
+        let covers = &covers[..1];
+        assert!(matches!(
+            encoder.encode_images(covers, &mut Cursor::new(&payload), &metadata),
+            Err(StegError::CoverTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn test_encode_decode_shares() {
+        let covers: Vec<image::DynamicImage> = (0..3)
+            .map(|_| image::load_from_memory(&cover_image(32, 32)).unwrap())
+            .collect();
+        let payload = "The treasure is buried under the old oak tree";
+
+        let encoder = Encoder::new(CompressInput::Gzip, ByteSplitGranularity::OneBit);
+        let images = encoder
+            .encode_shares(&covers, 2, &mut Cursor::new(payload))
+            .expect("no error");
+        assert_eq!(3, images.len());
+
+        let decoder = Decoder::new();
+        let shares: Vec<Share> = images
+            .iter()
+            .map(|img| decoder.inspect_image(img).unwrap().unwrap())
+            .map(|header| header.share().expect("share"))
+            .collect();
+        for (index, share) in shares.iter().enumerate() {
+            assert_eq!(index, share.index());
+            assert_eq!(2, share.threshold());
+            assert_eq!(3, share.count());
+            assert_eq!(shares[0].secret_id(), share.secret_id());
+        }
+
+        for (a, b) in [(0, 1), (2, 0), (1, 2)].iter() {
+            let given = [images[*a].clone(), images[*b].clone()];
+            assert_eq!(
+                payload.as_bytes().to_vec(),
+                decoder.decode_shares(&given).expect("no error")
+            );
+        }
+        assert_eq!(
+            payload.as_bytes().to_vec(),
+            decoder.decode_images(&images).expect("no error")
+        );
+
+        // The same share twice is still one share
+        let given = [images[1].clone(), images[1].clone()];
+        assert!(matches!(
+            decoder.decode_shares(&given),
+            Err(StegError::MissingShares {
+                given: 1,
+                threshold: 2
+            })
+        ));
+        assert!(matches!(
+            decoder.decode_image(&images[0]),
+            Err(StegError::MissingShares { .. })
+        ));
+
+        let other = encoder
+            .encode_shares(&covers[..2], 2, &mut Cursor::new(payload))
+            .expect("no error");
+        assert!(matches!(
+            decoder.decode_shares(&[images[0].clone(), other[1].clone()]),
+            Err(StegError::MixedShares)
+        ));
+        let plain = encoder
+            .encode_image(&covers[0], &mut Cursor::new(payload))
+            .expect("no error");
+        assert!(matches!(
+            decoder.decode_shares(&[images[0].clone(), plain]),
+            Err(StegError::MixedShares)
+        ));
+
+        assert!(matches!(
+            encoder.encode_shares(&covers, 4, &mut Cursor::new(payload)),
+            Err(StegError::InvalidMetadata(_))
+        ));
+    }
+
+    // Hands out the data a few bytes at a time, like a pipe
+    struct TrickleReader(Vec<u8>, usize);
+
+    impl Read for TrickleReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(7).min(self.0.len() - self.1);
+            buf[..n].copy_from_slice(&self.0[self.1..self.1 + n]);
+            self.1 += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_streamed_data() {
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let mut data = vec![0; 3000];
+        rng.fill_bytes(&mut data);
+
+        let mut encode_output: Vec<u8> = Vec::new();
+        Encoder::new(CompressInput::Gzip, ByteSplitGranularity::TwoBits)
+            .with_error_correction(0.1)
+            .with_scatter_key("scatter key")
+            .encode(
+                &mut BufReader::new(Cursor::new(cover_image(64, 64))),
+                &mut TrickleReader(data.clone(), 0),
+                &mut encode_output,
+            )
             .expect("no error");
 
+        let decoder = Decoder::new().with_scatter_key("scatter key");
+        assert_eq!(data, decode(&decoder, &encode_output).expect("no error"));
+
+        // Data that does not fit is still read to the end to tell how much space it needs
+        let err = Encoder::new(CompressInput::None, ByteSplitGranularity::FourBits)
+            .encode(
+                &mut BufReader::new(Cursor::new(cover_image(32, 32))),
+                &mut TrickleReader(data, 0),
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            StegError::CoverTooSmall { needed, available: 4096 } if needed > 6000
+        ));
+    }
+
+    #[test]
+    fn test_decode_version_1() {
+        let mut hasher = DefaultHasher::new();
+        b"Hey!".iter().for_each(|b| hasher.write_u8(*b));
+
+        let mut header = DataHeader::new(CompressInput::None, ByteSplitGranularity::TwoBits);
+        header.version = 0x1;
+        header.bytes_count = 4;
+        header.data_hash = hasher.finish();
+
+        let mut embedded = header.to_nibbles().unwrap();
+        assert_eq!(HEADER_LENGTH, embedded.len());
+        b"Hey!"
+            .iter()
+            .for_each(|b| embedded.extend(split_byte(ByteSplitGranularity::TwoBits, *b)));
+
+        let mut pixels = image::load_from_memory(&cover_image(32, 32))
+            .unwrap()
+            .to_rgba8();
+        embedded
+            .iter()
+            .zip(pixels.iter_mut())
+            .for_each(|(nibble, byte)| *byte = (*byte & 0xF0) | nibble);
+
+        let image = png_bytes(&image::DynamicImage::ImageRgba8(pixels));
+
+        let decode_output = decode(&Decoder::new(), &image).expect("no error");
         assert_eq!(
             String::from("Hey!"),
             String::from_utf8(decode_output).unwrap(),
         );
     }
+
+    fn cover_image(width: u32, height: u32) -> Vec<u8> {
+        let img = image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 7) as u8, (y * 13) as u8, ((x + y) * 3) as u8, 0xFF])
+        });
+
+        png_bytes(&image::DynamicImage::ImageRgba8(img))
+    }
+
+    // Inserts the chunks right after the IHDR chunk of a PNG image
+    fn with_chunks(png: Vec<u8>, chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut out = png[..33].to_vec();
+        for (chunk_type, data) in chunks {
+            let mut crc = !0u32;
+            for &b in chunk_type.iter().chain(data.iter()) {
+                crc ^= b as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 == 1 {
+                        (crc >> 1) ^ 0xEDB8_8320
+                    } else {
+                        crc >> 1
+                    };
+                }
+            }
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(*chunk_type);
+            out.extend_from_slice(data);
+            out.extend_from_slice(&(!crc).to_be_bytes());
+        }
+        out.extend_from_slice(&png[33..]);
+        out
+    }
+
+    fn png_bytes(img: &image::DynamicImage) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, image::ImageFormat::Png)
+            .expect("no error");
+        out.into_inner()
+    }
+
+    fn encode(encoder: &Encoder, data: &str) -> Vec<u8> {
+        let mut cover = BufReader::new(Cursor::new(cover_image(32, 32)));
+        let mut data = BufReader::new(Cursor::new(data.to_string()));
+        let mut encode_output: Vec<u8> = Vec::new();
+
+        encoder
+            .encode(&mut cover, &mut data, &mut encode_output)
+            .expect("no error");
+        encode_output
+    }
+
+    fn decode(decoder: &Decoder, image: &[u8]) -> Result<Vec<u8>, StegError> {
+        let mut decode_input = BufReader::new(Cursor::new(image.to_vec()));
+        let mut decode_output: Vec<u8> = Vec::new();
+
+        decoder.decode(&mut decode_input, &mut decode_output)?;
+        Ok(decode_output)
+    }
 }
@@ -0,0 +1,684 @@
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use image::ImageFormat;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use steg::analysis::ImageChannels;
+use steg::decoder::Decoder;
+use steg::encoder::Encoder;
+use steg::error::StegError;
+use steg::metadata::Metadata;
+use steg::quality::QualityReport;
+use steg::{ByteSplitGranularity, ChannelMask, CompressInput, EmbeddingMode};
+
+// Exit codes from sysexits.h, usage errors are reported by clap with exit code 2
+const EXIT_DATA_ERROR: u8 = 65;
+const EXIT_IO_ERROR: u8 = 74;
+
+#[derive(Parser)]
+#[command(
+    name = "steg",
+    version,
+    about = "Hide data in images and get it back out again",
+    after_help = "Paths given as \"-\" read from stdin or write to stdout"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Hide a payload in a cover image
+    Encode {
+        /// Cover image to hide the payload in
+        cover: PathBuf,
+        /// Payload to hide
+        #[arg(default_value = "-")]
+        payload: PathBuf,
+        /// Where to write the resulting image
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+        #[command(flatten)]
+        settings: EncodeSettings,
+        #[command(flatten)]
+        keys: Keys,
+        /// Refuse to write an output image whose PSNR is below this many dB
+        #[arg(long, value_name = "DB")]
+        min_psnr: Option<f64>,
+    },
+    /// Extract the payload hidden in an image
+    Decode {
+        /// Image carrying the payload
+        #[arg(default_value = "-")]
+        image: PathBuf,
+        /// Where to write the payload
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+        #[command(flatten)]
+        keys: Keys,
+    },
+    /// Split a payload across several cover images
+    Split {
+        /// Payload to hide
+        payload: PathBuf,
+        /// Cover images to hide the payload in, in order
+        #[arg(required = true)]
+        covers: Vec<PathBuf>,
+        /// Directory to write the resulting images to
+        #[arg(short, long, default_value = ".")]
+        output_dir: PathBuf,
+        /// Hide a Shamir share of the payload in every cover instead, so that any this many of
+        /// the images reconstruct it
+        #[arg(long, value_name = "K")]
+        threshold: Option<usize>,
+        #[command(flatten)]
+        settings: EncodeSettings,
+        #[command(flatten)]
+        keys: Keys,
+    },
+    /// Extract a payload that was split across several images or into shares, given in any order
+    Join {
+        /// Images carrying the fragments of the payload
+        #[arg(required = true)]
+        images: Vec<PathBuf>,
+        /// Where to write the payload
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+        #[command(flatten)]
+        keys: Keys,
+    },
+    /// Print the header of the payload hidden in an image
+    Inspect {
+        /// Image carrying the payload
+        #[arg(default_value = "-")]
+        image: PathBuf,
+        /// Key the payload was scattered with
+        #[arg(long, env = "STEG_SCATTER_KEY", hide_env_values = true)]
+        scatter_key: Option<String>,
+    },
+    /// Print how much an output image differs from its cover image
+    Compare {
+        /// Cover image the payload was hidden in
+        cover: PathBuf,
+        /// Image carrying the payload
+        #[arg(default_value = "-")]
+        image: PathBuf,
+    },
+    /// Write every bit plane of every channel of an image as a black and white PNG image
+    BitPlanes {
+        /// Image to take the bit planes of
+        #[arg(default_value = "-")]
+        image: PathBuf,
+        /// Directory to write the images to
+        #[arg(short, long, default_value = ".")]
+        output_dir: PathBuf,
+    },
+    /// Print how many bytes of payload fit into a cover image
+    Capacity {
+        /// Cover image to hide the payload in
+        #[arg(default_value = "-")]
+        cover: PathBuf,
+        #[command(flatten)]
+        settings: EncodeSettings,
+        /// Account for the encryption overhead
+        #[arg(long)]
+        encrypted: bool,
+    },
+}
+
+impl Command {
+    // Paths the command reads from, of which at most one can be stdin
+    fn inputs(&self) -> Vec<&Path> {
+        match self {
+            Command::Encode { cover, payload, .. } => vec![cover, payload],
+            Command::Decode { image, .. } => vec![image],
+            Command::Split {
+                payload, covers, ..
+            } => std::iter::once(payload)
+                .chain(covers)
+                .map(|p| p.as_path())
+                .collect(),
+            Command::Join { images, .. } => images.iter().map(|p| p.as_path()).collect(),
+            Command::Inspect { image, .. } => vec![image],
+            Command::Compare { cover, image } => vec![cover, image],
+            Command::BitPlanes { image, .. } => vec![image],
+            Command::Capacity { cover, .. } => vec![cover],
+        }
+    }
+
+    // Two inputs would both try to read stdin, which is a usage error like the ones clap reports
+    fn check_inputs(&self) -> Result<(), clap::Error> {
+        if self.inputs().iter().filter(|path| is_stdio(path)).count() > 1 {
+            return Err(Cli::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "only one of the inputs can be read from stdin (\"-\")",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+struct EncodeSettings {
+    /// Number of bits of every sample of the cover image used to carry the payload
+    #[arg(short, long, value_enum, default_value_t = Granularity::One)]
+    granularity: Granularity,
+    /// Compress the payload with gzip before hiding it, same as --compression gzip
+    #[arg(long, conflicts_with = "compression")]
+    gzip: bool,
+    /// Compress the payload before hiding it, auto keeps whichever codec compresses it best
+    #[arg(long, value_enum)]
+    compression: Option<Codec>,
+    /// Level of the zstd, brotli or xz compression, the codec's default otherwise
+    #[arg(long, requires = "compression", allow_hyphen_values = true)]
+    level: Option<i32>,
+    /// Lossless format of the resulting image: png, bmp, tiff, tga, pnm, qoi or webp
+    #[arg(short, long, value_parser = parse_format, default_value = "png")]
+    format: ImageFormat,
+    /// Share of Reed-Solomon parity bytes added to the payload to repair corrupted bytes, e.g. 0.1
+    #[arg(long, value_name = "REDUNDANCY")]
+    error_correction: Option<f64>,
+    /// Add or subtract one from samples instead of replacing their low bits, which is harder to
+    /// detect
+    #[arg(long)]
+    lsb_matching: bool,
+    /// Channels that carry the payload, any of r, g, b and a, e.g. rgb to leave alpha untouched
+    #[arg(long, value_parser = parse_channels, default_value = "rgba")]
+    channels: ChannelMask,
+    /// Only hide the payload in the colour channels of fully transparent pixels
+    #[arg(long)]
+    transparent_pixels: bool,
+}
+
+impl EncodeSettings {
+    fn encoder(&self) -> Encoder {
+        let level = |default: u32| self.level.map_or(default, |level| level.max(0) as u32);
+        let compress_input = match self.compression {
+            _ if self.gzip => CompressInput::Gzip,
+            None | Some(Codec::None) => CompressInput::None,
+            Some(Codec::Gzip) => CompressInput::Gzip,
+            Some(Codec::Zstd) => {
+                CompressInput::Zstd(self.level.unwrap_or(CompressInput::ZSTD_DEFAULT_LEVEL))
+            }
+            Some(Codec::Brotli) => {
+                CompressInput::Brotli(level(CompressInput::BROTLI_DEFAULT_LEVEL))
+            }
+            Some(Codec::Xz) => CompressInput::Xz(level(CompressInput::XZ_DEFAULT_LEVEL)),
+            Some(Codec::Auto) => CompressInput::Auto,
+        };
+
+        let granularity = match self.granularity {
+            Granularity::One => ByteSplitGranularity::OneBit,
+            Granularity::Two => ByteSplitGranularity::TwoBits,
+            Granularity::Four => ByteSplitGranularity::FourBits,
+        };
+
+        let embedding_mode = if self.lsb_matching {
+            EmbeddingMode::Matching
+        } else {
+            EmbeddingMode::Replacement
+        };
+
+        let mut encoder = Encoder::new(compress_input, granularity)
+            .with_output_format(self.format)
+            .with_embedding_mode(embedding_mode)
+            .with_channel_mask(self.channels);
+        if self.transparent_pixels {
+            encoder = encoder.with_transparent_pixels();
+        }
+        match self.error_correction {
+            Some(redundancy) => encoder.with_error_correction(redundancy),
+            None => encoder,
+        }
+    }
+}
+
+#[derive(Args)]
+struct Keys {
+    /// Passphrase the payload is encrypted with
+    #[arg(long, env = "STEG_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+    /// Key the payload is scattered across the image with
+    #[arg(long, env = "STEG_SCATTER_KEY", hide_env_values = true)]
+    scatter_key: Option<String>,
+}
+
+impl Keys {
+    fn encoder(&self, mut encoder: Encoder) -> Encoder {
+        if let Some(passphrase) = &self.passphrase {
+            encoder = encoder.with_passphrase(passphrase);
+        }
+        if let Some(key) = &self.scatter_key {
+            encoder = encoder.with_scatter_key(key);
+        }
+        encoder
+    }
+
+    fn decoder(&self) -> Decoder {
+        let mut decoder = Decoder::new();
+        if let Some(passphrase) = &self.passphrase {
+            decoder = decoder.with_passphrase(passphrase);
+        }
+        if let Some(key) = &self.scatter_key {
+            decoder = decoder.with_scatter_key(key);
+        }
+        decoder
+    }
+}
+
+fn parse_format(name: &str) -> Result<ImageFormat, String> {
+    ImageFormat::from_extension(name).ok_or_else(|| format!("unknown image format: {}", name))
+}
+
+const CHANNELS: [(char, ChannelMask); 4] = [
+    ('r', ChannelMask::RED),
+    ('g', ChannelMask::GREEN),
+    ('b', ChannelMask::BLUE),
+    ('a', ChannelMask::ALPHA),
+];
+
+fn parse_channels(channels: &str) -> Result<ChannelMask, String> {
+    let mut mask: Option<ChannelMask> = None;
+    for c in channels.chars() {
+        let (_, channel) = CHANNELS
+            .iter()
+            .find(|(name, _)| *name == c.to_ascii_lowercase())
+            .ok_or_else(|| format!("unknown channel: {}", c))?;
+        mask = Some(mask.map_or(*channel, |mask| mask | *channel));
+    }
+
+    mask.ok_or_else(|| "no channels given".to_string())
+}
+
+fn channel_names(mask: ChannelMask) -> String {
+    CHANNELS
+        .iter()
+        .filter(|(_, channel)| mask.contains(*channel))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Codec {
+    None,
+    Gzip,
+    Zstd,
+    Brotli,
+    Xz,
+    Auto,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Granularity {
+    One,
+    Two,
+    Four,
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let command = Cli::parse().command;
+    if let Err(err) = command.check_inputs() {
+        err.exit();
+    }
+
+    match run(command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("steg: {}", err);
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+fn run(command: Command) -> Result<(), StegError> {
+    match command {
+        Command::Encode {
+            cover,
+            payload,
+            output,
+            settings,
+            keys,
+            min_psnr,
+        } => {
+            let mut encoder = keys.encoder(settings.encoder());
+            if let Some(min_psnr) = min_psnr {
+                encoder = encoder.with_min_psnr(min_psnr);
+            }
+            if let Some(format) = extension_format(&cover) {
+                encoder = encoder.with_cover_format(format);
+            }
+
+            let cover = Cursor::new(read_all(&cover)?);
+            let mut payload = open(&payload)?;
+            let mut encode_output = Vec::new();
+            encoder.encode(cover, &mut payload, &mut encode_output)?;
+
+            Ok(write_all(&output, &encode_output)?)
+        }
+        Command::Decode {
+            image,
+            output,
+            keys,
+        } => {
+            let mut decoder = keys.decoder();
+            if let Some(format) = extension_format(&image) {
+                decoder = decoder.with_input_format(format);
+            }
+            let mut image = Cursor::new(read_all(&image)?);
+            let mut decode_output = Vec::new();
+            let report = decoder.decode_with_report(&mut image, &mut decode_output)?;
+            if report.corrected_symbols > 0 {
+                eprintln!(
+                    "steg: repaired {} corrupted bytes",
+                    report.corrected_symbols
+                );
+            }
+
+            Ok(write_all(&output, &decode_output)?)
+        }
+        Command::Split {
+            payload,
+            covers,
+            output_dir,
+            threshold,
+            settings,
+            keys,
+        } => {
+            let encoder = keys.encoder(settings.encoder());
+            let cover_images = covers
+                .iter()
+                .map(|cover| read_image(cover))
+                .collect::<Result<Vec<_>, StegError>>()?;
+
+            let mut payload = open(&payload)?;
+            let images = match threshold {
+                Some(threshold) => encoder.encode_shares(&cover_images, threshold, &mut payload)?,
+                None => encoder.encode_images(&cover_images, &mut payload, &Metadata::new())?,
+            };
+
+            // The images are numbered by the fragment or share they carry, which the decoder does not
+            // need, but keeps two covers with the same name apart
+            let extension = settings.format.extensions_str()[0];
+            for (index, (image, cover)) in images.iter().zip(covers.iter()).enumerate() {
+                let stem = cover
+                    .file_stem()
+                    .map_or("stdin".into(), |stem| stem.to_string_lossy());
+                let path = output_dir.join(format!("{}-{}.{}", stem, index, extension));
+
+                let mut out = Vec::new();
+                encoder.write_image(image, &mut out)?;
+                write_all(&path, &out)?;
+                println!("{}", path.display());
+            }
+
+            Ok(())
+        }
+        Command::Join {
+            images,
+            output,
+            keys,
+        } => {
+            let images = images
+                .iter()
+                .map(|image| read_image(image))
+                .collect::<Result<Vec<_>, StegError>>()?;
+
+            let (payload, report) = keys.decoder().decode_images_with_report(&images)?;
+            if report.corrected_symbols > 0 {
+                eprintln!(
+                    "steg: repaired {} corrupted bytes",
+                    report.corrected_symbols
+                );
+            }
+
+            Ok(write_all(&output, &payload)?)
+        }
+        Command::Inspect { image, scatter_key } => {
+            let mut decoder = Decoder::new();
+            if let Some(key) = &scatter_key {
+                decoder = decoder.with_scatter_key(key);
+            }
+            if let Some(format) = extension_format(&image) {
+                decoder = decoder.with_input_format(format);
+            }
+
+            let mut image = Cursor::new(read_all(&image)?);
+            let header = decoder.inspect(&mut image)?.ok_or_else(|| {
+                StegError::InvalidHeader("image does not carry a payload".to_string())
+            })?;
+
+            println!("version: {}", header.version());
+            println!("bytes: {}", header.bytes_count());
+            println!("compression: {:?}", header.compress_input());
+            println!("granularity: {:?}", header.granularity());
+            println!("embedding: {:?}", header.embedding_mode());
+            println!("channels: {}", channel_names(header.channel_mask()));
+            if header.transparent_pixels() {
+                println!("transparent pixels only");
+            }
+            if let Some(fragment) = header.fragment() {
+                println!(
+                    "fragment: {} of {} (message {:016x})",
+                    fragment.index(),
+                    fragment.count(),
+                    fragment.message_id()
+                );
+            }
+            if let Some(share) = header.share() {
+                println!(
+                    "share: {} of {}, {} needed (secret {:016x})",
+                    share.index(),
+                    share.count(),
+                    share.threshold(),
+                    share.secret_id()
+                );
+            }
+            if let Some(digest_kind) = header.digest_kind() {
+                println!("digest: {:?}", digest_kind);
+            }
+            println!("encrypted: {}", header.is_encrypted());
+            if let Some(ec) = header.error_correction() {
+                println!(
+                    "error correction: {} parity bytes per {} byte block",
+                    ec.parity_length(),
+                    ec.block_length()
+                );
+            }
+
+            let metadata = header.metadata();
+            if let Some(file_name) = &metadata.file_name {
+                println!("file name: {}", file_name);
+            }
+            if let Some(mime_type) = &metadata.mime_type {
+                println!("mime type: {}", mime_type);
+            }
+            if let Some(modified) = metadata.modified {
+                println!("modified: {:?}", modified);
+            }
+            for tag in metadata.tags.iter() {
+                println!("tag: {}", tag);
+            }
+
+            Ok(())
+        }
+        Command::Compare { cover, image } => {
+            let report = QualityReport::compare(&read_image(&cover)?, &read_image(&image)?)?;
+            println!("mse: {:.6}", report.mse);
+            println!("psnr: {:.2} dB", report.psnr);
+            println!("ssim: {:.6}", report.ssim);
+            println!("changed samples: {}", report.changed_samples);
+            println!("max delta: {}", report.max_delta);
+
+            Ok(())
+        }
+        Command::BitPlanes { image, output_dir } => {
+            let channels = ImageChannels::from_image(&read_image(&image)?);
+            for path in channels.write_bit_planes(&output_dir)? {
+                println!("{}", path.display());
+            }
+
+            Ok(())
+        }
+        Command::Capacity {
+            cover,
+            settings,
+            encrypted,
+        } => {
+            let mut encoder = settings.encoder();
+            // Only the presence of a passphrase matters for the capacity
+            if encrypted {
+                encoder = encoder.with_passphrase("");
+            }
+            if let Some(format) = extension_format(&cover) {
+                encoder = encoder.with_cover_format(format);
+            }
+
+            let cover = Cursor::new(read_all(&cover)?);
+            println!("{}", encoder.cover_capacity(cover, &Metadata::new())?);
+
+            Ok(())
+        }
+    }
+}
+
+// Failures to read or write the files are I/O errors, everything else is a problem with the
+// images or the payload
+fn exit_code(err: &StegError) -> u8 {
+    match err {
+        StegError::Io(_) | StegError::ImageFormat(image::ImageError::IoError(_)) => EXIT_IO_ERROR,
+        _ => EXIT_DATA_ERROR,
+    }
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn open(path: &Path) -> Result<Box<dyn Read>, std::io::Error> {
+    if is_stdio(path) {
+        Ok(Box::new(std::io::stdin().lock()))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+// Format of an image whose content does not tell it, such as TGA, taken from its file extension
+fn extension_format(path: &Path) -> Option<ImageFormat> {
+    ImageFormat::from_path(path).ok()
+}
+
+fn read_image(path: &Path) -> Result<image::DynamicImage, StegError> {
+    let bytes = read_all(path)?;
+    match extension_format(path) {
+        Some(format) if image::guess_format(&bytes).is_err() => {
+            Ok(image::load_from_memory_with_format(&bytes, format)?)
+        }
+        _ => Ok(image::load_from_memory(&bytes)?),
+    }
+}
+
+// Images are decoded from memory, as decoding needs to seek
+fn read_all(path: &Path) -> Result<Vec<u8>, std::io::Error> {
+    let mut data = Vec::new();
+    open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn write_all(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    if is_stdio(path) {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(data)?;
+        stdout.flush()
+    } else {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(data)?;
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_encode_arguments() {
+        let cli = Cli::try_parse_from([
+            "steg",
+            "encode",
+            "cover.png",
+            "-g",
+            "four",
+            "--gzip",
+            "--format",
+            "tif",
+            "--channels",
+            "RB",
+        ])
+        .expect("no error");
+
+        match cli.command {
+            Command::Encode {
+                payload,
+                output,
+                settings,
+                ..
+            } => {
+                assert!(is_stdio(&payload));
+                assert!(is_stdio(&output));
+                assert!(settings.gzip);
+                assert_eq!(ImageFormat::Tiff, settings.format);
+                assert_eq!(ChannelMask::RED | ChannelMask::BLUE, settings.channels);
+            }
+            _ => panic!("expected the encode command"),
+        }
+
+        let cli =
+            Cli::try_parse_from(["steg", "capacity", "--compression", "zstd", "--level", "-3"])
+                .expect("no error");
+        match cli.command {
+            Command::Capacity { settings, .. } => {
+                assert!(matches!(settings.compression, Some(Codec::Zstd)));
+                assert_eq!(Some(-3), settings.level);
+            }
+            _ => panic!("expected the capacity command"),
+        }
+
+        assert!(Cli::try_parse_from(["steg", "encode", "cover.png", "--level", "9"]).is_err());
+    }
+
+    #[test]
+    fn test_stdin_read_once() {
+        let cli = Cli::try_parse_from(["steg", "compare", "-"]).expect("no error");
+        let err = cli.command.check_inputs().unwrap_err();
+        assert_eq!(clap::error::ErrorKind::ArgumentConflict, err.kind());
+
+        let cli = Cli::try_parse_from(["steg", "encode", "-"]).expect("no error");
+        assert!(cli.command.check_inputs().is_err());
+
+        let cli = Cli::try_parse_from(["steg", "compare", "cover.png"]).expect("no error");
+        assert!(cli.command.check_inputs().is_ok());
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let io_error = || std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(EXIT_IO_ERROR, exit_code(&StegError::Io(io_error())));
+        assert_eq!(
+            EXIT_IO_ERROR,
+            exit_code(&StegError::ImageFormat(image::ImageError::IoError(
+                io_error()
+            )))
+        );
+        assert_eq!(EXIT_DATA_ERROR, exit_code(&StegError::WrongPassphrase));
+    }
+}
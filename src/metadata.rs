@@ -0,0 +1,95 @@
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Information about the hidden file, stored in the header next to the data
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Metadata {
+    pub file_name: Option<String>,
+    pub mime_type: Option<String>,
+    // Stored with a precision of one second
+    pub modified: Option<SystemTime>,
+    pub tags: Vec<String>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Name and modification time of the file at the given path
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let path = path.as_ref();
+        let modified = std::fs::metadata(path)?.modified().ok();
+
+        Ok(Self {
+            file_name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+            mime_type: None,
+            modified,
+            tags: Vec::new(),
+        })
+    }
+
+    pub fn with_file_name(mut self, file_name: &str) -> Self {
+        self.file_name = Some(file_name.to_string());
+        self
+    }
+
+    pub fn with_mime_type(mut self, mime_type: &str) -> Self {
+        self.mime_type = Some(mime_type.to_string());
+        self
+    }
+
+    pub fn with_modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+pub(crate) fn encode_modified(modified: SystemTime) -> Result<Vec<u8>, String> {
+    let seconds = modified
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "modification time is before the unix epoch".to_string())?
+        .as_secs();
+    Ok(seconds.to_be_bytes().to_vec())
+}
+
+pub(crate) fn decode_modified(data: &[u8]) -> Result<SystemTime, String> {
+    let seconds: [u8; 8] = data
+        .try_into()
+        .map_err(|_| "invalid modification time length".to_string())?;
+    // The value comes from the image, it may be out of the range SystemTime can hold
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(u64::from_be_bytes(seconds)))
+        .ok_or_else(|| "modification time is out of range".to_string())
+}
+
+pub(crate) fn decode_string(data: &[u8]) -> Result<String, String> {
+    String::from_utf8(data.to_vec()).map_err(|err| format!("invalid metadata string: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modified() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let encoded = encode_modified(modified).expect("no error");
+        assert_eq!(Ok(modified), decode_modified(&encoded));
+
+        assert!(decode_modified(&u64::MAX.to_be_bytes()).is_err());
+        assert!(decode_modified(&[0; 4]).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::error::StegError;
+use crate::{decoder, format, is_16_bit};
+use image::error::{ParameterError, ParameterErrorKind};
+use image::{ColorType, DynamicImage, GenericImageView};
+use std::io::Read;
+
+// Side of the square windows SSIM is computed over, and the distance between them
+const SSIM_WINDOW: usize = 8;
+const SSIM_STRIDE: usize = 4;
+
+// How much the output image differs from the cover image. The images are compared in the colour
+// type of the output, so that a cover converted to RGBA only differs where data was embedded
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityReport {
+    // Mean squared error of the samples
+    pub mse: f64,
+    // Peak signal to noise ratio in dB, infinite for identical images
+    pub psnr: f64,
+    // Mean structural similarity of the channels, 1 for identical images
+    pub ssim: f64,
+    // Number of samples, i.e. channel values, that changed
+    pub changed_samples: u64,
+    // Largest difference of a sample between the images
+    pub max_delta: u16,
+}
+
+impl QualityReport {
+    pub fn compare(cover: &DynamicImage, stego: &DynamicImage) -> Result<Self, StegError> {
+        if cover.dimensions() != stego.dimensions() {
+            return Err(StegError::ImageFormat(image::ImageError::Parameter(
+                ParameterError::from_kind(ParameterErrorKind::DimensionMismatch),
+            )));
+        }
+
+        let color_type = comparable_colour_type(stego.color());
+        let max = if is_16_bit(color_type) {
+            u16::MAX as f64
+        } else {
+            u8::MAX as f64
+        };
+        let (width, height) = stego.dimensions();
+        let cover = samples(&format::convert(cover.clone(), color_type));
+        let stego = samples(&format::convert(stego.clone(), color_type));
+
+        let mut squared_error = 0.0;
+        let mut changed_samples = 0;
+        let mut max_delta = 0;
+        for (c, s) in cover.iter().zip(stego.iter()) {
+            let delta = c.abs_diff(*s);
+            squared_error += (delta as f64).powi(2);
+            if delta > 0 {
+                changed_samples += 1;
+            }
+            max_delta = max_delta.max(delta);
+        }
+
+        let mse = if cover.is_empty() {
+            0.0
+        } else {
+            squared_error / cover.len() as f64
+        };
+        let psnr = if mse > 0.0 {
+            10.0 * (max * max / mse).log10()
+        } else {
+            f64::INFINITY
+        };
+
+        let channels = color_type.channel_count() as usize;
+        let channel = |samples: &[u16], c: usize| -> Vec<f64> {
+            samples
+                .iter()
+                .skip(c)
+                .step_by(channels)
+                .map(|s| *s as f64)
+                .collect()
+        };
+        let ssim = (0..channels)
+            .map(|c| {
+                ssim(
+                    &channel(&cover, c),
+                    &channel(&stego, c),
+                    width as usize,
+                    height as usize,
+                    max,
+                )
+            })
+            .sum::<f64>()
+            / channels as f64;
+
+        Ok(Self {
+            mse,
+            psnr,
+            ssim,
+            changed_samples,
+            max_delta,
+        })
+    }
+
+    // Compares a cover and an output image in any of the formats the decoder supports
+    pub fn read<R1: Read, R2: Read>(cover: &mut R1, stego: &mut R2) -> Result<Self, StegError> {
+        Self::compare(&decoder::load_image(cover)?, &decoder::load_image(stego)?)
+    }
+}
+
+// Images the encoder does not write are compared as RGBA
+fn comparable_colour_type(color_type: ColorType) -> ColorType {
+    match color_type {
+        ColorType::L8
+        | ColorType::La8
+        | ColorType::Rgb8
+        | ColorType::Rgba8
+        | ColorType::L16
+        | ColorType::La16
+        | ColorType::Rgb16
+        | ColorType::Rgba16 => color_type,
+        _ => ColorType::Rgba8,
+    }
+}
+
+fn samples(img: &DynamicImage) -> Vec<u16> {
+    match img {
+        DynamicImage::ImageLuma16(buf) => buf.as_raw().clone(),
+        DynamicImage::ImageLumaA16(buf) => buf.as_raw().clone(),
+        DynamicImage::ImageRgb16(buf) => buf.as_raw().clone(),
+        DynamicImage::ImageRgba16(buf) => buf.as_raw().clone(),
+        img => img.as_bytes().iter().map(|b| *b as u16).collect(),
+    }
+}
+
+// Mean SSIM of a channel over square windows, or over the whole channel when it is smaller than
+// a window
+fn ssim(cover: &[f64], stego: &[f64], width: usize, height: usize, max: f64) -> f64 {
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+    if window_width == 0 || window_height == 0 {
+        return 1.0;
+    }
+
+    let c1 = (0.01 * max).powi(2);
+    let c2 = (0.03 * max).powi(2);
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - window_height).step_by(SSIM_STRIDE) {
+        for left in (0..=width - window_width).step_by(SSIM_STRIDE) {
+            let positions = (top..top + window_height)
+                .flat_map(|y| (left..left + window_width).map(move |x| y * width + x));
+            let n = (window_width * window_height) as f64;
+
+            let (mut sum_x, mut sum_y, mut sum_xx, mut sum_yy, mut sum_xy) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for p in positions {
+                let (x, y) = (cover[p], stego[p]);
+                sum_x += x;
+                sum_y += y;
+                sum_xx += x * x;
+                sum_yy += y * y;
+                sum_xy += x * y;
+            }
+
+            let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+            let variance_x = sum_xx / n - mean_x * mean_x;
+            let variance_y = sum_yy / n - mean_y * mean_y;
+            let covariance = sum_xy / n - mean_x * mean_y;
+
+            total += ((2.0 * mean_x * mean_y + c1) * (2.0 * covariance + c2))
+                / ((mean_x * mean_x + mean_y * mean_y + c1) * (variance_x + variance_y + c2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient() -> image::RgbImage {
+        image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        })
+    }
+
+    #[test]
+    fn test_identical_images() {
+        let img = DynamicImage::ImageRgb8(gradient());
+        let report = QualityReport::compare(&img, &img).expect("no error");
+
+        assert_eq!(0.0, report.mse);
+        assert!(report.psnr.is_infinite());
+        assert!((report.ssim - 1.0).abs() < 1e-9);
+        assert_eq!(0, report.changed_samples);
+        assert_eq!(0, report.max_delta);
+    }
+
+    #[test]
+    fn test_changed_images() {
+        let cover = gradient();
+        let mut stego = cover.clone();
+        stego.get_pixel_mut(0, 0).0[0] += 2;
+        stego.get_pixel_mut(5, 3).0[2] -= 1;
+
+        let report = QualityReport::compare(
+            &DynamicImage::ImageRgb8(cover),
+            &DynamicImage::ImageRgb8(stego),
+        )
+        .expect("no error");
+
+        let mse = 5.0 / (16.0 * 16.0 * 3.0);
+        assert!((report.mse - mse).abs() < 1e-12);
+        assert!((report.psnr - 10.0 * (255.0 * 255.0 / mse).log10()).abs() < 1e-9);
+        assert!(report.ssim < 1.0 && report.ssim > 0.99);
+        assert_eq!(2, report.changed_samples);
+        assert_eq!(2, report.max_delta);
+    }
+
+    #[test]
+    fn test_dimension_mismatch() {
+        let cover = DynamicImage::ImageRgb8(gradient());
+        let stego = DynamicImage::new_rgb8(8, 8);
+        assert!(matches!(
+            QualityReport::compare(&cover, &stego),
+            Err(StegError::ImageFormat(_))
+        ));
+    }
+}
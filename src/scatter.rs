@@ -0,0 +1,144 @@
+use crate::error::StegError;
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::collections::HashMap;
+
+// Fixed salt, the seed only has to be reproducible from the key
+const SEED_SALT: &[u8] = b"steg scatter order";
+// Argon2id costs of the seed. They are not recorded in the header, so changing them, or leaving
+// them to the defaults of the argon2 crate, changes the order of every scattered image
+const SEED_M_COST: u32 = 19 * 1024;
+const SEED_T_COST: u32 = 2;
+const SEED_P_COST: u32 = 1;
+
+pub type Seed = [u8; 32];
+
+// Stretches the key into the seed of the permutation, so that guessing a weak key is expensive
+pub fn derive_seed(key: &str) -> Result<Seed, StegError> {
+    let params = Params::new(SEED_M_COST, SEED_T_COST, SEED_P_COST, Some(32))
+        .map_err(|err| StegError::Crypto(format!("invalid key derivation parameters: {}", err)))?;
+
+    let mut seed: Seed = [0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(key.as_bytes(), SEED_SALT, &mut seed)
+        .map_err(|err| StegError::Crypto(format!("key derivation failure: {}", err)))?;
+    Ok(seed)
+}
+
+// Order in which the bytes of the cover image are used to carry the header and data.
+// Without a seed the bytes are used sequentially from the start of the image
+pub fn positions(len: usize, seed: Option<Seed>) -> Box<dyn Iterator<Item = usize>> {
+    match seed {
+        Some(seed) => Box::new(ScatterOrder::new(len, seed)),
+        None => Box::new(0..len),
+    }
+}
+
+// A Fisher-Yates shuffle of 0..len driven by ChaCha20, evaluated lazily so that only the swapped
+// positions have to be remembered rather than the whole permutation. That is still about one map
+// entry per position taken, which for large payloads outgrows the image itself. The indices are
+// drawn with below rather than rand's range sampling, whose algorithm may change between versions
+// and depends on the width of usize, as the order has to stay the same for images to decode
+struct ScatterOrder {
+    rng: ChaCha20Rng,
+    len: usize,
+    next: usize,
+    swapped: HashMap<usize, usize>,
+}
+
+impl ScatterOrder {
+    fn new(len: usize, seed: Seed) -> Self {
+        Self {
+            rng: ChaCha20Rng::from_seed(seed),
+            len,
+            next: 0,
+            swapped: HashMap::new(),
+        }
+    }
+
+    // A uniformly distributed number in 0..bound, from 64 bit draws of the ChaCha20 stream.
+    // Draws from the incomplete multiple of bound at the top of the range are rejected
+    fn below(&mut self, bound: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % bound;
+        loop {
+            let x = self.rng.next_u64();
+            if x < zone {
+                return x % bound;
+            }
+        }
+    }
+}
+
+impl Iterator for ScatterOrder {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.next >= self.len {
+            return None;
+        }
+
+        let i = self.next;
+        let j = i + self.below((self.len - i) as u64) as usize;
+
+        let at_i = self.swapped.remove(&i).unwrap_or(i);
+        let at_j = if i == j {
+            at_i
+        } else {
+            self.swapped.insert(j, at_i).unwrap_or(j)
+        };
+
+        self.next += 1;
+        Some(at_j)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scatter_order_is_a_permutation() {
+        let mut order: Vec<usize> = positions(1000, Some([7; 32])).collect();
+        assert_ne!((0..1000).collect::<Vec<usize>>(), order);
+
+        order.sort_unstable();
+        assert_eq!((0..1000).collect::<Vec<usize>>(), order);
+    }
+
+    #[test]
+    fn test_scatter_order_is_reproducible() {
+        let first: Vec<usize> = positions(1000, Some([7; 32])).take(100).collect();
+        let second: Vec<usize> = positions(1000, Some([7; 32])).take(100).collect();
+        let other: Vec<usize> = positions(1000, Some([8; 32])).take(100).collect();
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    // Images embedded with a scatter key only decode as long as the order stays exactly the same
+    #[test]
+    fn test_scatter_order_known_answer() {
+        let order: Vec<usize> = positions(1000, Some([7; 32])).take(10).collect();
+        assert_eq!(vec![308, 830, 478, 430, 429, 420, 4, 970, 159, 792], order);
+    }
+
+    // Same for the seed derived from the key
+    #[test]
+    fn test_derive_seed_known_answer() {
+        let seed = derive_seed("scatter key").expect("no error");
+        assert_eq!(
+            [
+                143, 65, 185, 236, 14, 205, 76, 110, 199, 87, 28, 93, 240, 60, 139, 59, 29, 136,
+                143, 58, 85, 253, 130, 102, 63, 62, 162, 217, 49, 129, 251, 172
+            ],
+            seed
+        );
+    }
+
+    #[test]
+    fn test_sequential_order() {
+        let order: Vec<usize> = positions(5, None).collect();
+        assert_eq!(vec![0, 1, 2, 3, 4], order);
+    }
+}
@@ -0,0 +1,91 @@
+// Shamir's secret sharing over GF(256). Every byte of the secret is the constant term of its own
+// random polynomial of degree threshold - 1, share i holds the values of the polynomials at
+// x = i + 1. Any threshold shares determine the polynomials, fewer are consistent with every
+// possible secret
+use crate::gf256;
+use rand::RngCore;
+
+pub fn split<R: RngCore>(
+    secret: &[u8],
+    threshold: usize,
+    count: usize,
+    rng: &mut R,
+) -> Vec<Vec<u8>> {
+    assert!(
+        (1..=count).contains(&threshold) && count <= 255,
+        "invalid threshold"
+    );
+
+    let mut shares = vec![Vec::with_capacity(secret.len()); count];
+    // Highest degree coefficient first, the secret byte goes last
+    let mut poly = vec![0; threshold];
+    for byte in secret {
+        rng.fill_bytes(&mut poly[..threshold - 1]);
+        poly[threshold - 1] = *byte;
+        for (i, share) in shares.iter_mut().enumerate() {
+            share.push(gf256::eval(&poly, (i + 1) as u8));
+        }
+    }
+    shares
+}
+
+// Recovers the secret from shares given as (index, share), which must have distinct indices,
+// the same length and be at least as many as the threshold they were split with
+pub fn combine(shares: &[(usize, &[u8])]) -> Vec<u8> {
+    let xs: Vec<u8> = shares.iter().map(|(i, _)| (*i + 1) as u8).collect();
+
+    // Lagrange basis polynomials at x = 0, subtraction is addition in GF(256)
+    let basis: Vec<u8> = xs
+        .iter()
+        .enumerate()
+        .map(|(i, xi)| {
+            xs.iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .fold(1, |acc, (_, xj)| gf256::mul(acc, gf256::div(*xj, xj ^ xi)))
+        })
+        .collect();
+
+    let len = shares.first().map_or(0, |(_, share)| share.len());
+    (0..len)
+        .map(|b| {
+            shares
+                .iter()
+                .zip(basis.iter())
+                .fold(0, |acc, ((_, share), l)| acc ^ gf256::mul(*l, share[b]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_split_combine() {
+        let secret = b"attack at dawn".to_vec();
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+        let shares = split(&secret, 3, 5, &mut rng);
+        assert_eq!(5, shares.len());
+        assert!(shares.iter().all(|share| *share != secret));
+
+        for a in 0..5 {
+            for b in a + 1..5 {
+                for c in b + 1..5 {
+                    let given = [(c, &shares[c][..]), (a, &shares[a]), (b, &shares[b])];
+                    assert_eq!(secret, combine(&given));
+                }
+            }
+        }
+
+        // Two shares only determine a line, which does not go through the secret
+        let given = [(0, &shares[0][..]), (1, &shares[1][..])];
+        assert_ne!(secret, combine(&given));
+
+        // With a threshold of one every share is the secret
+        let shares = split(&secret, 1, 2, &mut rng);
+        assert_eq!(secret, shares[1]);
+    }
+}